@@ -1,39 +1,247 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Principal};
+#[cfg(not(test))]
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::collections::{BTreeMap, BTreeSet};
 use std::{borrow::Cow, cell::RefCell};
 
 // Define the type aliases for memory and ID counter management
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+// Number of nanoseconds in one hour
+const NANOS_PER_HOUR: u64 = 3_600 * 1_000_000_000;
+
+// Number of nanoseconds in one calendar day, used to bucket timestamps by day
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+// Number of nanoseconds in a 7-day week, used for weekly buckets
+const NANOS_PER_WEEK: u64 = 7 * NANOS_PER_DAY;
+
+// Longest recommendation text accepted from callers, in bytes
+const MAX_RECOMMENDATION_LEN: usize = 256;
+
+// Size budget for list-style responses, kept well under the 2 MB reply limit
+const MAX_RESPONSE_BYTES: usize = 1_500_000;
+
+// Longest series, in days, that the per-day chart queries will build
+const MAX_SERIES_DAYS: u64 = 3_660;
+
+// Most buckets a sparkline series can be split into
+const MAX_SPARKLINE_BUCKETS: u64 = 1_000;
+
+// Hard cap on stored records; inserts beyond it fail with MemoryFull
+const MAX_RECORDS: u64 = 100_000;
+
+// Record count at which inserts start reporting near_capacity by default
+const DEFAULT_WARN_AT_RECORDS: u64 = MAX_RECORDS * 9 / 10;
+
+// Longest device type accepted as a key in the device-keyed stable maps, in bytes
+const MAX_DEVICE_TYPE_LEN: usize = 64;
+
+// Grid carbon intensity used until one is configured (roughly the global average)
+const DEFAULT_GRID_INTENSITY_G_PER_KWH: f64 = 475.0;
+
+// CO2 a mature tree absorbs in a year, used to express emissions as trees
+const KG_CO2_PER_TREE_PER_YEAR: f64 = 21.0;
+
+// Currency code reported for costs until one is configured
+const DEFAULT_CURRENCY: &str = "USD";
+
+// Longest currency code accepted, in bytes
+const MAX_CURRENCY_LEN: usize = 8;
+
+// Decimal places used for reported cost and CO2 figures until configured
+const DEFAULT_DECIMAL_PLACES: u8 = 2;
+
+// Most sub-meter components a composite record can carry
+const MAX_COMPONENTS: usize = 16;
+
+// Most tags a single record can carry
+const MAX_TAGS: usize = 4;
+
+// Longest tag accepted, in bytes
+const MAX_TAG_LEN: usize = 32;
+
+// Longest energy source name accepted, in bytes
+const MAX_ENERGY_SOURCE_LEN: usize = 16;
+
+// Readings above these kWh values get the Moderate and High recommendations
+// until the thresholds are configured
+const DEFAULT_MODERATE_THRESHOLD_KWH: f64 = 5.0;
+const DEFAULT_HIGH_THRESHOLD_KWH: f64 = 10.0;
+
+// Longest attachment URL accepted, in bytes
+const MAX_ATTACHMENT_URL_LEN: usize = 200;
+
+// Longest raw row and rejection reason kept for a quarantined import row
+const MAX_QUARANTINE_ROW_LEN: usize = 512;
+const MAX_QUARANTINE_REASON_LEN: usize = 256;
+
+// Format version written as the first byte of a backup blob
+const BACKUP_VERSION: u8 = 1;
+
+// Largest configurable number of decimal places for reported figures
+const MAX_DECIMAL_PLACES: u8 = 6;
+
 // Struct to store energy usage details
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct EnergyUsage {
     id: u64,                     // Unique identifier for each record
     usage_kwh: f64,              // Energy usage in kilowatt-hours
     timestamp: u64,              // Time of the recorded usage (in nanoseconds since epoch)
     device_type: String,         // Type of device consuming the energy
     recommendation: Option<String>, // Optional energy-saving recommendation
+    is_adjustment: bool,         // Credit/correction entry that may carry negative usage
+    renewable_percent: f64,      // Share of this usage supplied by renewables (0-100)
+    owner: Principal,            // Principal that created the record
+    estimated: bool,             // True for estimated rather than metered readings
+    components: Vec<f64>,        // Sub-meter kWh values summed into usage_kwh, if composite
+    tags: Vec<String>,           // Free-form labels for grouping records
+    energy_source: String,       // Supply source, e.g. "grid" or "solar"; empty when unknown
+    cost_override: Option<f64>,  // Actual cost when billed at a special rate
+    attachment_url: Option<String>, // Link to a meter photo or receipt stored elsewhere
+}
+
+// Principal has no Default, so records default to the anonymous owner
+impl Default for EnergyUsage {
+    fn default() -> Self {
+        EnergyUsage {
+            id: 0,
+            usage_kwh: 0.0,
+            timestamp: 0,
+            device_type: String::new(),
+            recommendation: None,
+            is_adjustment: false,
+            renewable_percent: 0.0,
+            owner: Principal::anonymous(),
+            estimated: false,
+            components: Vec::new(),
+            tags: Vec::new(),
+            energy_source: String::new(),
+            cost_override: None,
+            attachment_url: None,
+        }
+    }
 }
 
 // Implement the Storable trait to allow serialization/deserialization
 impl Storable for EnergyUsage {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), StoredEnergyUsage).unwrap().into()
+    }
+}
+
+// Decoding view of a stored record. Candid only tolerates a missing field when
+// it is optional, so fields added after records were first stored are read
+// as options here and fall back to their defaults for older records. The
+// original canister did not limit device types, so longer ones are cut to
+// MAX_DEVICE_TYPE_LEN to keep rewritten records within MAX_RECORD_SIZE.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredEnergyUsage {
+    id: u64,
+    usage_kwh: f64,
+    timestamp: u64,
+    device_type: String,
+    recommendation: Option<String>,
+    is_adjustment: Option<bool>,
+    renewable_percent: Option<f64>,
+    owner: Option<Principal>,
+    estimated: Option<bool>,
+    components: Option<Vec<f64>>,
+    tags: Option<Vec<String>>,
+    energy_source: Option<String>,
+    cost_override: Option<f64>,
+    attachment_url: Option<String>,
+}
+
+impl From<StoredEnergyUsage> for EnergyUsage {
+    fn from(stored: StoredEnergyUsage) -> Self {
+        EnergyUsage {
+            id: stored.id,
+            usage_kwh: stored.usage_kwh,
+            timestamp: stored.timestamp,
+            device_type: truncate_str(&stored.device_type, MAX_DEVICE_TYPE_LEN),
+            recommendation: stored.recommendation,
+            is_adjustment: stored.is_adjustment.unwrap_or(false),
+            renewable_percent: stored.renewable_percent.unwrap_or(0.0),
+            owner: stored.owner.unwrap_or_else(Principal::anonymous),
+            estimated: stored.estimated.unwrap_or(false),
+            components: stored.components.unwrap_or_default(),
+            tags: stored.tags.unwrap_or_default(),
+            energy_source: stored.energy_source.unwrap_or_default(),
+            cost_override: stored.cost_override,
+            attachment_url: stored.attachment_url,
+        }
     }
 }
 
+// Upper bound on the Candid-encoded size of a record, in bytes. The worst
+// case with every field at its limit is about 980 bytes: the ~120 byte type
+// header, 8-byte id/usage/timestamp/renewable_percent/cost_override, a
+// MAX_DEVICE_TYPE_LEN device type, a MAX_RECOMMENDATION_LEN recommendation, a
+// 29-byte owner, MAX_COMPONENTS 8-byte components, MAX_TAGS tags of
+// MAX_TAG_LEN bytes, a MAX_ENERGY_SOURCE_LEN energy source and a
+// MAX_ATTACHMENT_URL_LEN attachment URL. Records stored by the original
+// canister may hold longer device types, which are cut to the limit when read
+// (see StoredEnergyUsage). 1024 still covers that, and new fields should be
+// sized to keep it so: stable maps refuse to load with a larger bound than the
+// one they were created with, so raising it needs a migration for canisters
+// that already hold data.
+const MAX_RECORD_SIZE: u32 = 1024;
+
 // Implement the BoundedStorable trait to set size limits for storage
 impl BoundedStorable for EnergyUsage {
-    const MAX_SIZE: u32 = 1024; // Maximum storage size in bytes
+    const MAX_SIZE: u32 = MAX_RECORD_SIZE; // Maximum storage size in bytes
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// An import row set aside because it could not be imported
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuarantinedRow {
+    raw_row: String, // The row as supplied, cut to MAX_QUARANTINE_ROW_LEN
+    reason: String,  // Why it was rejected, cut to MAX_QUARANTINE_REASON_LEN
+}
+
+impl Storable for QuarantinedRow {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// The two bounded strings plus the Candid header stay well under 1024 bytes
+impl BoundedStorable for QuarantinedRow {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Bounded string key for the stable maps keyed by device type or energy source
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DeviceKey(String);
+
+impl Storable for DeviceKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        DeviceKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for DeviceKey {
+    const MAX_SIZE: u32 = MAX_DEVICE_TYPE_LEN as u32;
     const IS_FIXED_SIZE: bool = false;
 }
 
@@ -52,45 +260,345 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
         ));
+
+    static ALLOWED_DEVICE_TYPES: RefCell<StableBTreeMap<DeviceKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        ));
+
+    static ALERT_THRESHOLD_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))), 0.0)
+            .expect("Cannot create the alert threshold")
+    );
+
+    static GRID_INTENSITY_G_PER_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            DEFAULT_GRID_INTENSITY_G_PER_KWH,
+        )
+        .expect("Cannot create the grid intensity")
+    );
+
+    static SAVINGS_GOAL_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), 0.0)
+            .expect("Cannot create the savings goal")
+    );
+
+    // Raw bytes of the admin principal; empty until an admin is set
+    static ADMIN: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), Vec::new())
+            .expect("Cannot create the admin cell")
+    );
+
+    static TARIFF_PER_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))), 0.0)
+            .expect("Cannot create the tariff")
+    );
+
+    static CURRENCY: RefCell<Cell<String, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            DEFAULT_CURRENCY.to_string(),
+        )
+        .expect("Cannot create the currency")
+    );
+
+    static DECIMAL_PLACES: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            DEFAULT_DECIMAL_PLACES,
+        )
+        .expect("Cannot create the decimal places")
+    );
+
+    // 1 while new inserts are accepted, 0 while they are paused
+    static ACCEPTING_INSERTS: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), 1)
+            .expect("Cannot create the insert flag")
+    );
+
+    static DEVICE_BASELINES: RefCell<StableBTreeMap<DeviceKey, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        ));
+
+    static DAILY_SNAPSHOT: RefCell<StableBTreeMap<u64, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        ));
+
+    // Decimal places incoming readings are rounded to; the maximum leaves
+    // readings effectively unrounded
+    static INPUT_PRECISION: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            MAX_DECIMAL_PLACES,
+        )
+        .expect("Cannot create the input precision")
+    );
+
+    // Rated power of each registered device, keyed by device type
+    static DEVICE_RATED_WATTS: RefCell<StableBTreeMap<DeviceKey, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        ));
+
+    static WARN_AT_RECORDS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+            DEFAULT_WARN_AT_RECORDS,
+        )
+        .expect("Cannot create the record warning threshold")
+    );
+
+    static MONTHLY_BUDGET_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), 0.0)
+            .expect("Cannot create the monthly budget")
+    );
+
+    // Carbon intensity in g CO2 per kWh of each configured energy source
+    static EMISSION_FACTORS: RefCell<StableBTreeMap<DeviceKey, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        ));
+
+    static MODERATE_THRESHOLD_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+            DEFAULT_MODERATE_THRESHOLD_KWH,
+        )
+        .expect("Cannot create the moderate threshold")
+    );
+
+    static HIGH_THRESHOLD_KWH: RefCell<Cell<f64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            DEFAULT_HIGH_THRESHOLD_KWH,
+        )
+        .expect("Cannot create the high threshold")
+    );
+
+    // Number of people in the household; 0 until configured
+    static HOUSEHOLD_SIZE: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), 0)
+            .expect("Cannot create the household size")
+    );
+
+    // Version byte followed by the Candid-encoded records; empty until the
+    // first backup
+    static BACKUP: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), Vec::new())
+            .expect("Cannot create the backup")
+    );
+
+    static QUARANTINE: RefCell<StableBTreeMap<u64, QuarantinedRow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        ));
+
+    // Monthly usage quota in kWh of each device type that has one
+    static DEVICE_QUOTAS: RefCell<StableBTreeMap<DeviceKey, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        ));
+}
+
+// Current time in nanoseconds since epoch; unit tests run outside a canister,
+// where time() traps, so they read a clock they can set instead
+#[cfg(not(test))]
+fn now() -> u64 {
+    time()
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_TIME: RefCell<u64> = const { RefCell::new(0) };
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+    TEST_TIME.with(|t| *t.borrow())
+}
+
+// Principal of the current caller; unit tests set it explicitly for the
+// same reason as now()
+#[cfg(not(test))]
+fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+}
+
+#[cfg(test)]
+fn caller() -> Principal {
+    TEST_CALLER.with(|c| *c.borrow())
+}
+
+// Units a meter may report energy in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum EnergyUnit {
+    Wh,
+    Kwh,
+    Mwh,
+}
+
+impl EnergyUnit {
+    // Convert an amount in this unit to kilowatt-hours
+    fn to_kwh(self, amount: f64) -> f64 {
+        match self {
+            EnergyUnit::Wh => amount / 1_000.0,
+            EnergyUnit::Kwh => amount,
+            EnergyUnit::Mwh => amount * 1_000.0,
+        }
+    }
 }
 
 // Struct for input payload to add new energy usage
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
 struct EnergyUsagePayload {
-    usage_kwh: f64,              // Energy usage in kilowatt-hours
+    usage_kwh: f64,              // Energy usage, in `unit` (kilowatt-hours by default)
     device_type: String,         // Type of device consuming the energy
+    unit: Option<EnergyUnit>,    // Unit of usage_kwh; treated as Kwh when absent
+    renewable_percent: Option<f64>, // Renewable share of the usage (0-100); 0 when absent
+    estimated: Option<bool>,     // Whether the reading is estimated; metered when absent
+    energy_source: Option<String>, // Supply source of the usage; unknown when absent
+}
+
+// Result of adding a new energy usage record
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AddEnergyUsageResponse {
+    record: EnergyUsage, // The stored record
+    alert: bool,         // True when the reading exceeds the alert threshold
+    near_capacity: bool, // True once the record count reaches warn_at_records
 }
 
 // Add a new energy usage record
 #[ic_cdk::update]
-fn add_energy_usage(payload: EnergyUsagePayload) -> Result<EnergyUsage, Error> {
-    // Validate input data
-    if payload.usage_kwh <= 0.0 || payload.device_type.is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Usage must be greater than 0 and device type cannot be empty.".to_string(),
-        });
-    }
+fn add_energy_usage(payload: EnergyUsagePayload) -> Result<AddEnergyUsageResponse, Error> {
+    ensure_accepting_inserts()?;
+
+    // Validate input data and normalize its precision
+    let usage_kwh = normalized_usage(&payload)?;
 
     // Increment the ID counter to create a unique ID
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    let id = next_id();
 
     // Create a new EnergyUsage record
     let energy_usage = EnergyUsage {
         id,
-        usage_kwh: payload.usage_kwh,
-        timestamp: time(),
+        usage_kwh,
+        timestamp: now(),
         device_type: payload.device_type.clone(),
-        recommendation: Some(generate_recommendation(payload.usage_kwh)),
+        recommendation: Some(generate_recommendation(usage_kwh)),
+        is_adjustment: false,
+        renewable_percent: payload.renewable_percent.unwrap_or(0.0),
+        owner: caller(),
+        estimated: payload.estimated.unwrap_or(false),
+        components: Vec::new(),
+        tags: Vec::new(),
+        energy_source: payload.energy_source.unwrap_or_default(),
+        cost_override: None,
+        attachment_url: None,
     };
 
     // Insert the new record into storage
     do_insert(&energy_usage)?;
-    Ok(energy_usage)
+    Ok(AddEnergyUsageResponse {
+        alert: exceeds_alert_threshold(energy_usage.usage_kwh),
+        near_capacity: record_count() >= get_warn_at_records(),
+        record: energy_usage,
+    })
+}
+
+// Validate a payload the way add_energy_usage does, returning its usage
+// converted to kWh so every stored record and aggregate shares one unit
+fn validate_usage_payload(payload: &EnergyUsagePayload) -> Result<f64, Error> {
+    let usage_kwh = payload.unit.unwrap_or(EnergyUnit::Kwh).to_kwh(payload.usage_kwh);
+    if !usage_kwh.is_finite() || usage_kwh <= 0.0 || payload.device_type.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Usage must be greater than 0 and device type cannot be empty.".to_string(),
+        });
+    }
+    validate_device_type(&payload.device_type)?;
+    if !is_device_type_allowed(&payload.device_type) {
+        return Err(Error::InvalidInput {
+            msg: format!("Device type '{}' is not in the allow-list.", payload.device_type),
+        });
+    }
+    if let Some(source) = &payload.energy_source {
+        validate_energy_source(source)?;
+    }
+    if let Some(percent) = payload.renewable_percent {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(Error::InvalidInput {
+                msg: "Renewable percent must be between 0 and 100.".to_string(),
+            });
+        }
+    }
+    Ok(usage_kwh)
+}
+
+// Validate a payload and round its usage to the configured input precision
+fn normalized_usage(payload: &EnergyUsagePayload) -> Result<f64, Error> {
+    let usage_kwh = round_to(validate_usage_payload(payload)?, get_input_precision());
+    if usage_kwh <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Usage rounds to 0 at the configured input precision.".to_string(),
+        });
+    }
+    Ok(usage_kwh)
+}
+
+// Dry-run the add_energy_usage validation without storing anything, so a UI
+// can show errors inline before submitting
+#[ic_cdk::query]
+fn validate_payload(payload: EnergyUsagePayload) -> Result<(), Error> {
+    normalized_usage(&payload).map(|_| ())
+}
+
+// Check a reading against the alert threshold; a threshold of 0 disables alerts
+fn exceeds_alert_threshold(usage_kwh: f64) -> bool {
+    let threshold = get_alert_threshold();
+    threshold > 0.0 && usage_kwh > threshold
+}
+
+// Retrieve the single-reading alert threshold in kWh (0 means disabled)
+#[ic_cdk::query]
+fn get_alert_threshold() -> f64 {
+    ALERT_THRESHOLD_KWH.with(|cell| *cell.borrow().get())
+}
+
+// Set the single-reading alert threshold in kWh; 0 disables alerting
+#[ic_cdk::update]
+fn set_alert_threshold(threshold_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    store_alert_threshold(threshold_kwh)
+}
+
+// Validate and persist the alert threshold
+fn store_alert_threshold(threshold_kwh: f64) -> Result<(), Error> {
+    if !threshold_kwh.is_finite() || threshold_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Alert threshold must be a non-negative number.".to_string(),
+        });
+    }
+    ALERT_THRESHOLD_KWH
+        .with(|cell| cell.borrow_mut().set(threshold_kwh))
+        .expect("Cannot store the alert threshold");
+    Ok(())
+}
+
+// Helper function to allocate the next unique record ID
+fn next_id() -> u64 {
+    ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter")
 }
 
 // Helper function to insert an energy usage record into storage
@@ -101,14 +609,38 @@ fn do_insert(energy_usage: &EnergyUsage) -> Result<(), Error> {
     Ok(())
 }
 
+// Advice tiers a reading can fall into
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum RecommendationLevel {
+    Low,
+    Moderate,
+    High,
+}
+
+// Classify a reading into its recommendation tier using the configured
+// thresholds
+fn classify_usage(usage_kwh: f64) -> RecommendationLevel {
+    let (moderate, high) = get_recommendation_thresholds();
+    if usage_kwh > high {
+        RecommendationLevel::High
+    } else if usage_kwh > moderate {
+        RecommendationLevel::Moderate
+    } else {
+        RecommendationLevel::Low
+    }
+}
+
 // Generate energy-saving recommendations based on usage
 fn generate_recommendation(usage_kwh: f64) -> String {
-    if usage_kwh > 10.0 {
-        "High energy usage detected. Consider reducing the number of devices or optimizing usage.".to_string()
-    } else if usage_kwh > 5.0 {
-        "Moderate energy usage. Consider using energy-efficient devices.".to_string()
-    } else {
-        "Low energy usage. Keep up the good work!".to_string()
+    recommendation_for_level(classify_usage(usage_kwh))
+}
+
+// The advice text for a recommendation tier
+fn recommendation_for_level(level: RecommendationLevel) -> String {
+    match level {
+        RecommendationLevel::High => "High energy usage detected. Consider reducing the number of devices or optimizing usage.".to_string(),
+        RecommendationLevel::Moderate => "Moderate energy usage. Consider using energy-efficient devices.".to_string(),
+        RecommendationLevel::Low => "Low energy usage. Keep up the good work!".to_string(),
     }
 }
 
@@ -128,6 +660,30 @@ fn _get_energy_usage(id: &u64) -> Option<EnergyUsage> {
     STORAGE.with(|s| s.borrow().get(id))
 }
 
+// Internal helper function to fetch every stored record in ID order
+fn all_records() -> Vec<EnergyUsage> {
+    STORAGE.with(|s| s.borrow().iter().map(|(_, usage)| usage).collect())
+}
+
+// Average usage per calendar day, counting only the days that have readings
+// so that empty days don't inflate the denominator
+#[ic_cdk::query]
+fn average_daily_usage() -> Result<f64, Error> {
+    let records = all_records();
+    if records.is_empty() {
+        return Err(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        });
+    }
+
+    let total: f64 = records.iter().map(|usage| usage.usage_kwh).sum();
+    let days: BTreeSet<u64> = records
+        .iter()
+        .map(|usage| usage.timestamp / NANOS_PER_DAY)
+        .collect();
+    Ok(total / days.len() as f64)
+}
+
 // Delete an energy usage record by ID
 #[ic_cdk::update]
 fn delete_energy_usage(id: u64) -> Result<EnergyUsage, Error> {
@@ -139,41 +695,5665 @@ fn delete_energy_usage(id: u64) -> Result<EnergyUsage, Error> {
     }
 }
 
-// Define custom error types for the system
-#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
-enum Error {
-    NotFound { msg: String },    // Record not found
-    MemoryFull { msg: String },  // Storage limit reached
-    InvalidInput { msg: String }, // Invalid input provided
+// Summary of records that fail basic sanity checks
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug)]
+struct IntegrityReport {
+    records_checked: u64,                // Number of records inspected
+    negative_usage_ids: Vec<u64>,        // Records with usage_kwh below zero
+    missing_recommendation_ids: Vec<u64>, // Records without a recommendation
 }
 
-// Export the Candid interface for the canister
-ic_cdk::export_candid!();
+// Inspect every stored record and report the ones that need repair
+#[ic_cdk::query]
+fn integrity_report() -> IntegrityReport {
+    STORAGE.with(|s| {
+        let mut report = IntegrityReport::default();
+        for (id, usage) in s.borrow().iter() {
+            report.records_checked += 1;
+            // Adjustments are credits: negative usage and no advice are expected
+            if usage.is_adjustment {
+                continue;
+            }
+            if usage.usage_kwh < 0.0 {
+                report.negative_usage_ids.push(id);
+            }
+            if usage.recommendation.is_none() {
+                report.missing_recommendation_ids.push(id);
+            }
+        }
+        report
+    })
+}
 
-// Integration tests to verify functionality
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Repair the records flagged by integrity_report and return how many changed.
+// Negative usage is clamped to 0 rather than deleted so no reading disappears,
+// and the recommendation is regenerated whenever it is missing or the usage
+// was clamped. Valid records are left as they are, so repeated runs converge.
+// Admin only, as it rewrites every user's records.
+#[ic_cdk::update]
+fn repair_records() -> Result<u64, Error> {
+    ensure_admin()?;
+    Ok(STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        let broken: Vec<EnergyUsage> = storage
+            .iter()
+            .map(|(_, usage)| usage)
+            .filter(|usage| !usage.is_adjustment)
+            .filter(|usage| usage.usage_kwh < 0.0 || usage.recommendation.is_none())
+            .collect();
 
-    #[test]
-    fn test_add_and_get_energy_usage() {
-        let payload = EnergyUsagePayload {
-            usage_kwh: 12.0,
-            device_type: "Air Conditioner".to_string(),
-        };
-        let record = add_energy_usage(payload).unwrap();
-        assert_eq!(record.usage_kwh, 12.0);
-        assert!(get_energy_usage(record.id).is_ok());
+        let repaired = broken.len() as u64;
+        for mut usage in broken {
+            if usage.usage_kwh < 0.0 {
+                usage.usage_kwh = 0.0;
+                usage.recommendation = None;
+            }
+            if usage.recommendation.is_none() {
+                usage.recommendation = Some(generate_recommendation(usage.usage_kwh));
+            }
+            storage.insert(usage.id, usage);
+        }
+        repaired
+    }))
+}
+
+// Manually override a record's recommendation; an empty text clears it
+#[ic_cdk::update]
+fn set_recommendation(id: u64, text: String) -> Result<EnergyUsage, Error> {
+    let recommendation = validate_recommendation(text)?;
+    let mut usage = get_energy_usage(id)?;
+    usage.recommendation = recommendation;
+    do_insert(&usage)?;
+    Ok(usage)
+}
+
+// Bound caller-supplied recommendation text; an empty text means "no advice"
+fn validate_recommendation(text: String) -> Result<Option<String>, Error> {
+    if text.len() > MAX_RECOMMENDATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Recommendation cannot be longer than {} bytes.",
+                MAX_RECOMMENDATION_LEN
+            ),
+        });
     }
+    Ok(if text.is_empty() { None } else { Some(text) })
+}
 
-    #[test]
-    fn test_delete_energy_usage() {
-        let payload = EnergyUsagePayload {
-            usage_kwh: 5.0,
-            device_type: "Laptop".to_string(),
-        };
-        let record = add_energy_usage(payload).unwrap();
-        assert!(delete_energy_usage(record.id).is_ok());
-        assert!(get_energy_usage(record.id).is_err());
+// Write the same recommendation to every non-adjustment record currently in
+// the given tier, returning how many records changed; admin only
+#[ic_cdk::update]
+fn set_recommendation_for_tier(tier: RecommendationLevel, text: String) -> Result<u64, Error> {
+    ensure_admin()?;
+    let recommendation = validate_recommendation(text)?;
+    let changed: Vec<EnergyUsage> = all_records()
+        .into_iter()
+        .filter(|usage| !usage.is_adjustment && classify_usage(usage.usage_kwh) == tier)
+        .filter(|usage| usage.recommendation != recommendation)
+        .collect();
+
+    for mut usage in changed.iter().cloned() {
+        usage.recommendation = recommendation.clone();
+        do_insert(&usage)?;
+    }
+    Ok(changed.len() as u64)
+}
+
+// A list-style response, cut short when it would not fit in a single reply
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct EnergyUsagePage {
+    records: Vec<EnergyUsage>, // Records that fit within the response budget
+    truncated: bool,           // True when some records were left out
+}
+
+// A JSON export, cut short when it would not fit in a single reply
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct JsonExport {
+    json: String,    // JSON array of the exported records
+    truncated: bool, // True when some records were left out
+}
+
+// Keep the leading items whose estimated encoded size fits under
+// MAX_RESPONSE_BYTES, reporting whether anything had to be dropped
+fn truncate_to_fit<T>(items: Vec<T>, size_of: impl Fn(&T) -> usize) -> (Vec<T>, bool) {
+    let mut budget = MAX_RESPONSE_BYTES;
+    let total = items.len();
+    let kept: Vec<T> = items
+        .into_iter()
+        .take_while(|item| match budget.checked_sub(size_of(item)) {
+            Some(remaining) => {
+                budget = remaining;
+                true
+            }
+            None => false,
+        })
+        .collect();
+    let truncated = kept.len() < total;
+    (kept, truncated)
+}
+
+// List every energy usage record in ID order, truncated to fit a response
+#[ic_cdk::query]
+fn list_all_energy_usage() -> EnergyUsagePage {
+    let (records, truncated) = truncate_to_fit(all_records(), |usage| usage.to_bytes().len());
+    EnergyUsagePage { records, truncated }
+}
+
+// Export every energy usage record as JSON, truncated to fit a response
+#[ic_cdk::query]
+fn export_json() -> JsonExport {
+    let (records, truncated) = truncate_to_fit(all_records(), |usage| {
+        serde_json::to_vec(usage).map_or(0, |bytes| bytes.len() + 1)
+    });
+    JsonExport {
+        json: serde_json::to_string(&records).expect("Cannot serialize records"),
+        truncated,
+    }
+}
+
+// Check a device type against the allow-list; an empty list allows anything
+fn is_device_type_allowed(device_type: &str) -> bool {
+    ALLOWED_DEVICE_TYPES.with(|allowed| {
+        let allowed = allowed.borrow();
+        allowed.is_empty() || allowed.contains_key(&DeviceKey(device_type.to_string()))
+    })
+}
+
+// Validate a device type before using it as a stable map key
+fn device_key(device_type: String) -> Result<DeviceKey, Error> {
+    validate_device_type(&device_type)?;
+    Ok(DeviceKey(device_type))
+}
+
+// Device types must be non-empty and fit the bound used for stored records
+fn validate_device_type(device_type: &str) -> Result<(), Error> {
+    if device_type.is_empty() || device_type.len() > MAX_DEVICE_TYPE_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Device type must be between 1 and {} bytes long.",
+                MAX_DEVICE_TYPE_LEN
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Add a device type to the allow-list enforced by add_energy_usage
+#[ic_cdk::update]
+fn add_allowed_device_type(device_type: String) -> Result<(), Error> {
+    ensure_admin()?;
+    let key = device_key(device_type)?;
+    ALLOWED_DEVICE_TYPES.with(|allowed| allowed.borrow_mut().insert(key, ()));
+    Ok(())
+}
+
+// Remove a device type from the allow-list
+#[ic_cdk::update]
+fn remove_allowed_device_type(device_type: String) -> Result<(), Error> {
+    ensure_admin()?;
+    let key = DeviceKey(device_type.clone());
+    match ALLOWED_DEVICE_TYPES.with(|allowed| allowed.borrow_mut().remove(&key)) {
+        Some(()) => Ok(()),
+        None => Err(Error::NotFound {
+            msg: format!("Device type '{}' is not in the allow-list.", device_type),
+        }),
+    }
+}
+
+// List the allowed device types; an empty list means anything is accepted
+#[ic_cdk::query]
+fn list_allowed_device_types() -> Vec<String> {
+    ALLOWED_DEVICE_TYPES.with(|allowed| allowed.borrow().iter().map(|(key, _)| key.0).collect())
+}
+
+// Single-pass (Welford) accumulator for the mean and variance of readings
+#[derive(Default)]
+struct RunningStats {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    // Population variance; fewer than two readings have no spread
+    fn variance(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.m2 / self.count as f64
+        }
+    }
+
+    // Sample (n - 1) variance; fewer than two readings have no spread
+    fn sample_variance(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.m2 / (self.count - 1) as f64
+        }
+    }
+}
+
+// Accumulate running statistics of usage_kwh for each device type
+fn stats_by_device() -> BTreeMap<String, RunningStats> {
+    STORAGE.with(|s| {
+        let mut stats: BTreeMap<String, RunningStats> = BTreeMap::new();
+        for (_, usage) in s.borrow().iter() {
+            stats.entry(usage.device_type).or_default().push(usage.usage_kwh);
+        }
+        stats
+    })
+}
+
+// Usage variance per device type, most erratic first
+#[ic_cdk::query]
+fn usage_variance_by_device() -> Vec<(String, f64)> {
+    let mut variances: Vec<(String, f64)> = stats_by_device()
+        .into_iter()
+        .map(|(device_type, stats)| (device_type, stats.variance()))
+        .collect();
+    variances.sort_by(|a, b| b.1.total_cmp(&a.1));
+    variances
+}
+
+// Return the first n and the last n records by ID. When there are fewer than
+// 2n records the two slices never overlap: the first slice is filled up to n
+// and the last slice gets whatever remains.
+#[ic_cdk::query]
+fn sample_ends(n: u64) -> (Vec<EnergyUsage>, Vec<EnergyUsage>) {
+    let mut records = all_records();
+    let first_len = records.len().min(n as usize);
+    let last_len = (records.len() - first_len).min(n as usize);
+    let last = records.split_off(records.len() - last_len);
+    records.truncate(first_len);
+    (records, last)
+}
+
+// Record a credit or correction, e.g. solar export under net metering. Unlike
+// add_energy_usage the delta may be negative; the entry carries no
+// recommendation and is flagged so reports can treat it as an adjustment.
+#[ic_cdk::update]
+fn add_adjustment(device_type: String, delta_kwh: f64) -> Result<EnergyUsage, Error> {
+    ensure_accepting_inserts()?;
+    if !delta_kwh.is_finite() || delta_kwh == 0.0 || device_type.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Adjustment must be a non-zero amount and device type cannot be empty."
+                .to_string(),
+        });
+    }
+    validate_device_type(&device_type)?;
+
+    let adjustment = EnergyUsage {
+        id: next_id(),
+        usage_kwh: delta_kwh,
+        timestamp: now(),
+        device_type,
+        recommendation: None,
+        is_adjustment: true,
+        renewable_percent: 0.0,
+        owner: caller(),
+        estimated: false,
+        components: Vec::new(),
+        tags: Vec::new(),
+        energy_source: String::new(),
+        cost_override: None,
+        attachment_url: None,
+    };
+    do_insert(&adjustment)?;
+    Ok(adjustment)
+}
+
+// Total usage across all records, netting in any adjustments
+#[ic_cdk::query]
+fn total_usage_kwh() -> f64 {
+    total_usage(true)
+}
+
+// Total usage of metered readings only, leaving estimated readings out
+#[ic_cdk::query]
+fn total_usage_metered_only() -> f64 {
+    total_usage(false)
+}
+
+// Sum usage across all records, optionally excluding estimated readings.
+// This total is the only aggregate that takes the flag: every other aggregate
+// (averages, per-device and per-period totals, costs and CO2) counts estimated
+// readings like metered ones.
+#[ic_cdk::query]
+fn total_usage(include_estimated: bool) -> f64 {
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| include_estimated || !usage.estimated)
+            .map(|(_, usage)| usage.usage_kwh)
+            .sum()
+    })
+}
+
+// Find stretches between consecutive readings (by timestamp) that are longer
+// than max_gap_ns, returned as (start_ns, end_ns) pairs; needs two records
+#[ic_cdk::query]
+fn reading_gaps(max_gap_ns: u64) -> Vec<(u64, u64)> {
+    let mut timestamps: Vec<u64> = all_records().iter().map(|usage| usage.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > max_gap_ns)
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
+}
+
+// Load factor: mean usage divided by peak usage across all records. Values
+// near 1 mean steady consumption, low values mean spiky consumption.
+#[ic_cdk::query]
+fn load_factor() -> Result<f64, Error> {
+    let mut stats = RunningStats::default();
+    let mut peak = f64::MIN;
+    for usage in all_records() {
+        stats.push(usage.usage_kwh);
+        peak = peak.max(usage.usage_kwh);
+    }
+
+    if stats.count == 0 {
+        return Err(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        });
+    }
+    if peak <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Peak usage must be greater than 0 to compute a load factor.".to_string(),
+        });
+    }
+    Ok(stats.mean / peak)
+}
+
+// Clone an existing record under a fresh ID and the current time, keeping its
+// usage and descriptive fields and regenerating the recommendation
+#[ic_cdk::update]
+fn duplicate_record(id: u64) -> Result<EnergyUsage, Error> {
+    ensure_accepting_inserts()?;
+    let source = get_energy_usage(id)?;
+    let copy = EnergyUsage {
+        id: next_id(),
+        timestamp: now(),
+        owner: caller(),
+        recommendation: if source.is_adjustment {
+            None
+        } else {
+            Some(generate_recommendation(source.usage_kwh))
+        },
+        ..source
+    };
+    do_insert(&copy)?;
+    Ok(copy)
+}
+
+// Running total of usage in ID order, as (id, cumulative_kwh) pairs
+#[ic_cdk::query]
+fn cumulative_usage() -> Vec<(u64, f64)> {
+    let mut running_total = 0.0;
+    all_records()
+        .into_iter()
+        .map(|usage| {
+            running_total += usage.usage_kwh;
+            (usage.id, running_total)
+        })
+        .collect()
+}
+
+// Records grouped by the recommendation tier their usage falls into
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TierBuckets {
+    low: Vec<EnergyUsage>,
+    moderate: Vec<EnergyUsage>,
+    high: Vec<EnergyUsage>,
+    truncated: bool, // True when some records were left out to fit a response
+}
+
+// Bucket every record by recommendation tier, in ID order within each tier.
+// Records are cut in ID order before bucketing, so together the tiers fit a
+// single response.
+#[ic_cdk::query]
+fn records_by_tier() -> TierBuckets {
+    let (records, truncated) = truncate_to_fit(all_records(), |usage| usage.to_bytes().len());
+    let mut buckets = TierBuckets {
+        truncated,
+        ..Default::default()
+    };
+    for usage in records {
+        match classify_usage(usage.usage_kwh) {
+            RecommendationLevel::Low => buckets.low.push(usage),
+            RecommendationLevel::Moderate => buckets.moderate.push(usage),
+            RecommendationLevel::High => buckets.high.push(usage),
+        }
+    }
+    buckets
+}
+
+// Linearly extrapolate total usage over the recorded span to a 365-day year.
+// The span runs from the earliest to the latest reading and must cover at
+// least one full day.
+#[ic_cdk::query]
+fn projected_annual_usage() -> Result<f64, Error> {
+    Ok(average_daily_rate()? * 365.0)
+}
+
+// Total usage divided by the number of days between the earliest and latest
+// readings; fails with InvalidInput when that span is under one day
+fn average_daily_rate() -> Result<f64, Error> {
+    Ok(total_usage_kwh() / projection_span_days()?)
+}
+
+// Days between the earliest and latest readings, which must be at least one
+fn projection_span_days() -> Result<f64, Error> {
+    let (first, last) = time_span().unwrap_or((0, 0));
+    if last - first < NANOS_PER_DAY {
+        return Err(Error::InvalidInput {
+            msg: "At least one full day of data is required for a projection.".to_string(),
+        });
+    }
+    Ok((last - first) as f64 / NANOS_PER_DAY as f64)
+}
+
+// Retrieve the configured grid carbon intensity in grams of CO2 per kWh
+#[ic_cdk::query]
+fn get_grid_intensity() -> f64 {
+    GRID_INTENSITY_G_PER_KWH.with(|cell| *cell.borrow().get())
+}
+
+// Set the grid carbon intensity used when CO2 queries get no explicit value
+#[ic_cdk::update]
+fn set_grid_intensity(grid_intensity_g_per_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    validate_grid_intensity(grid_intensity_g_per_kwh)?;
+    GRID_INTENSITY_G_PER_KWH
+        .with(|cell| cell.borrow_mut().set(grid_intensity_g_per_kwh))
+        .expect("Cannot store the grid intensity");
+    Ok(())
+}
+
+// Reject carbon intensities that are negative or not a number
+fn validate_grid_intensity(grid_intensity_g_per_kwh: f64) -> Result<(), Error> {
+    if !grid_intensity_g_per_kwh.is_finite() || grid_intensity_g_per_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Grid intensity must be a non-negative number.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Use the explicit intensity when given, otherwise the configured one
+fn resolve_grid_intensity(grid_intensity_g_per_kwh: Option<f64>) -> Result<f64, Error> {
+    let intensity = grid_intensity_g_per_kwh.unwrap_or_else(get_grid_intensity);
+    validate_grid_intensity(intensity)?;
+    Ok(intensity)
+}
+
+// Estimate the CO2 emitted by all recorded usage, in kilograms
+#[ic_cdk::query]
+fn estimate_co2_kg(grid_intensity_g_per_kwh: Option<f64>) -> Result<f64, Error> {
+    Ok(round_reported(total_co2_kg(grid_intensity_g_per_kwh)?))
+}
+
+// Unrounded CO2 of all recorded usage, in kilograms. This is the CO2 model
+// every CO2 query shares: the renewable share of a reading (renewable_kwh) is
+// emission-free, and the rest emits at the emission factor of the record's
+// energy source, or at the grid intensity when the source has none.
+fn total_co2_kg(grid_intensity_g_per_kwh: Option<f64>) -> Result<f64, Error> {
+    let intensity = resolve_grid_intensity(grid_intensity_g_per_kwh)?;
+    let grams: f64 = EMISSION_FACTORS.with(|factors| {
+        let factors = factors.borrow();
+        all_records()
+            .into_iter()
+            .map(|usage| {
+                let emitting_kwh = usage.usage_kwh - renewable_kwh(&usage);
+                let factor = factors.get(&DeviceKey(usage.energy_source)).unwrap_or(intensity);
+                emitting_kwh * factor
+            })
+            .sum()
+    });
+    Ok(grams / 1_000.0)
+}
+
+// Number of trees needed to absorb the estimated CO2 within a year
+#[ic_cdk::query]
+fn trees_to_offset(grid_intensity_g_per_kwh: Option<f64>) -> Result<u64, Error> {
+    let co2_kg = total_co2_kg(grid_intensity_g_per_kwh)?;
+    Ok((co2_kg.max(0.0) / KG_CO2_PER_TREE_PER_YEAR).ceil() as u64)
+}
+
+// Device type with the most readings and its count. Device types are grouped
+// case-insensitively, reported with the casing of their earliest record, and
+// ties go to the alphabetically first device type.
+#[ic_cdk::query]
+fn most_frequent_device() -> Result<(String, u64), Error> {
+    let mut counts: BTreeMap<String, (String, u64)> = BTreeMap::new();
+    for usage in all_records() {
+        let entry = counts
+            .entry(usage.device_type.to_lowercase())
+            .or_insert_with(|| (usage.device_type.clone(), 0));
+        entry.1 += 1;
+    }
+
+    // BTreeMap iterates alphabetically, so keeping only strictly larger counts
+    // leaves the alphabetically first device type on a tie
+    counts
+        .into_values()
+        .fold(None, |best: Option<(String, u64)>, candidate| match best {
+            Some(best) if best.1 >= candidate.1 => Some(best),
+            _ => Some(candidate),
+        })
+        .ok_or(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        })
+}
+
+// Snapshot of how record IDs have been allocated
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct IdDiagnostics {
+    next_id: u64,          // Value of the ID counter, i.e. the next ID to hand out
+    used_id_count: u64,    // Records currently present
+    missing_id_count: u64, // Allocated IDs with no record, e.g. after deletes
+}
+
+// Report ID counter state and how many allocated IDs are now unused
+#[ic_cdk::query]
+fn id_diagnostics() -> IdDiagnostics {
+    let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let used_id_count = STORAGE.with(|s| s.borrow().len());
+    IdDiagnostics {
+        next_id,
+        used_id_count,
+        missing_id_count: next_id.saturating_sub(used_id_count),
+    }
+}
+
+// Sum usage into fixed-width time buckets, keyed by the number of whole
+// bucket widths since the Unix epoch
+fn totals_by_bucket(width_ns: u64) -> BTreeMap<u64, f64> {
+    STORAGE.with(|s| {
+        let mut totals: BTreeMap<u64, f64> = BTreeMap::new();
+        for (_, usage) in s.borrow().iter() {
+            *totals.entry(usage.timestamp / width_ns).or_default() += usage.usage_kwh;
+        }
+        totals
+    })
+}
+
+// Total usage per 7-day week since the epoch, as (week, total_kwh) pairs in
+// chronological order. Plain 7-day periods keep the buckets consistent without
+// the full ISO-8601 week rules.
+#[ic_cdk::query]
+fn weekly_totals() -> Vec<(u64, f64)> {
+    totals_by_bucket(NANOS_PER_WEEK).into_iter().collect()
+}
+
+// Portion of a record's usage supplied by renewables, in kWh
+fn renewable_kwh(usage: &EnergyUsage) -> f64 {
+    usage.usage_kwh * usage.renewable_percent / 100.0
+}
+
+// Split total usage into (renewable_kwh, nonrenewable_kwh) using each
+// record's renewable_percent
+#[ic_cdk::query]
+fn renewable_breakdown() -> (f64, f64) {
+    all_records().iter().fold((0.0, 0.0), |(renewable, nonrenewable), usage| {
+        let green = renewable_kwh(usage);
+        (renewable + green, nonrenewable + usage.usage_kwh - green)
+    })
+}
+
+// Total usage of the records owned by the caller, without transferring them
+#[ic_cdk::query]
+fn my_total_usage() -> f64 {
+    let me = caller();
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| usage.owner == me)
+            .map(|(_, usage)| usage.usage_kwh)
+            .sum()
+    })
+}
+
+// Maximum encoded size of a stored record, so clients can avoid building
+// payloads that would not fit
+#[ic_cdk::query]
+fn max_record_size() -> u32 {
+    MAX_RECORD_SIZE
+}
+
+// All records stamped with exactly the given time, in ID order
+#[ic_cdk::query]
+fn get_by_timestamp(timestamp_ns: u64) -> Vec<EnergyUsage> {
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| usage.timestamp == timestamp_ns)
+            .map(|(_, usage)| usage)
+            .collect()
+    })
+}
+
+// Progress towards the savings goal relative to a caller-supplied baseline
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct GoalProgress {
+    goal_kwh: f64,         // Configured reduction target
+    reduction_kwh: f64,    // Baseline minus current total (negative if usage grew)
+    progress_percent: f64, // Reduction as a share of the goal, floored at 0
+}
+
+// Set the targeted usage reduction in kWh; 0 clears the goal
+#[ic_cdk::update]
+fn set_savings_goal(goal_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    if !goal_kwh.is_finite() || goal_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Savings goal must be a non-negative number.".to_string(),
+        });
+    }
+    SAVINGS_GOAL_KWH
+        .with(|cell| cell.borrow_mut().set(goal_kwh))
+        .expect("Cannot store the savings goal");
+    Ok(())
+}
+
+// Compare current total usage against a baseline to measure goal progress.
+// Progress can exceed 100% once the goal is beaten, and is 0% when usage
+// grew or no goal is set.
+#[ic_cdk::query]
+fn goal_progress(baseline_kwh: f64) -> GoalProgress {
+    let goal_kwh = SAVINGS_GOAL_KWH.with(|cell| *cell.borrow().get());
+    let reduction_kwh = baseline_kwh - total_usage_kwh();
+    let progress_percent = if goal_kwh > 0.0 {
+        (reduction_kwh / goal_kwh * 100.0).max(0.0)
+    } else {
+        0.0
+    };
+    GoalProgress {
+        goal_kwh,
+        reduction_kwh,
+        progress_percent,
+    }
+}
+
+// Records sorted by timestamp, with ID breaking ties
+fn records_by_time() -> Vec<EnergyUsage> {
+    let mut records = all_records();
+    records.sort_by_key(|usage| (usage.timestamp, usage.id));
+    records
+}
+
+// Change in usage from one reading to the next, in timestamp order, as
+// (id, delta_kwh) pairs; the first reading has a delta of 0
+#[ic_cdk::query]
+fn usage_deltas() -> Vec<(u64, f64)> {
+    let mut previous: Option<f64> = None;
+    records_by_time()
+        .into_iter()
+        .map(|usage| {
+            let delta = previous.map_or(0.0, |prev| usage.usage_kwh - prev);
+            previous = Some(usage.usage_kwh);
+            (usage.id, delta)
+        })
+        .collect()
+}
+
+// Coefficient of variation (stddev / mean) per device type, most volatile
+// first. Devices with fewer than two readings or a zero mean are skipped.
+#[ic_cdk::query]
+fn device_cov() -> Vec<(String, f64)> {
+    let mut covs: Vec<(String, f64)> = stats_by_device()
+        .into_iter()
+        .filter(|(_, stats)| stats.count >= 2 && stats.mean != 0.0)
+        .map(|(device_type, stats)| (device_type, stats.variance().sqrt() / stats.mean))
+        .collect();
+    covs.sort_by(|a, b| b.1.total_cmp(&a.1));
+    covs
+}
+
+// Retrieve the admin principal, if one has been set
+#[ic_cdk::query]
+fn get_admin() -> Option<Principal> {
+    ADMIN.with(|cell| {
+        let bytes = cell.borrow().get().clone();
+        (!bytes.is_empty()).then(|| Principal::from_slice(&bytes))
+    })
+}
+
+// Store the admin principal
+fn store_admin(admin: Principal) {
+    ADMIN
+        .with(|cell| cell.borrow_mut().set(admin.as_slice().to_vec()))
+        .expect("Cannot store the admin");
+}
+
+// Guard for configuration-changing updates. While no admin is set, the first
+// authenticated caller of a guarded update becomes the admin; anonymous
+// callers can never claim or hold the role.
+fn ensure_admin() -> Result<(), Error> {
+    let caller = caller();
+    match get_admin() {
+        Some(admin) if admin == caller => Ok(()),
+        None if caller != Principal::anonymous() => {
+            store_admin(caller);
+            Ok(())
+        }
+        _ => Err(Error::Unauthorized {
+            msg: "Only the admin can change the configuration.".to_string(),
+        }),
+    }
+}
+
+// Retrieve the flat tariff charged per kWh
+#[ic_cdk::query]
+fn get_tariff() -> f64 {
+    TARIFF_PER_KWH.with(|cell| *cell.borrow().get())
+}
+
+// Set the flat tariff charged per kWh
+#[ic_cdk::update]
+fn set_tariff(rate_per_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    store_tariff(rate_per_kwh)
+}
+
+// Validate and persist the tariff
+fn store_tariff(rate_per_kwh: f64) -> Result<(), Error> {
+    validate_rate(rate_per_kwh)?;
+    TARIFF_PER_KWH
+        .with(|cell| cell.borrow_mut().set(rate_per_kwh))
+        .expect("Cannot store the tariff");
+    Ok(())
+}
+
+// Reject rates that are negative or not a number
+fn validate_rate(rate_per_kwh: f64) -> Result<(), Error> {
+    if !rate_per_kwh.is_finite() || rate_per_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Rate must be a non-negative number.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Retrieve the currency code costs are reported in
+#[ic_cdk::query]
+fn get_currency() -> String {
+    CURRENCY.with(|cell| cell.borrow().get().clone())
+}
+
+// Set the currency code costs are reported in
+#[ic_cdk::update]
+fn set_currency(currency: String) -> Result<(), Error> {
+    ensure_admin()?;
+    store_currency(currency)
+}
+
+// Validate and persist the currency code
+fn store_currency(currency: String) -> Result<(), Error> {
+    if currency.is_empty() || currency.len() > MAX_CURRENCY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Currency must be between 1 and {} bytes long.",
+                MAX_CURRENCY_LEN
+            ),
+        });
+    }
+    CURRENCY
+        .with(|cell| cell.borrow_mut().set(currency))
+        .expect("Cannot store the currency");
+    Ok(())
+}
+
+// Configuration that can be seeded at install time or changed on upgrade;
+// every field is optional and absent fields keep their current value
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct InitConfig {
+    tariff_per_kwh: Option<f64>,
+    alert_threshold_kwh: Option<f64>,
+    currency: Option<String>,
+    admin: Option<Principal>,
+}
+
+// Validate and persist every value supplied in an InitConfig
+fn apply_init_config(config: InitConfig) -> Result<(), Error> {
+    if let Some(tariff) = config.tariff_per_kwh {
+        store_tariff(tariff)?;
+    }
+    if let Some(threshold) = config.alert_threshold_kwh {
+        store_alert_threshold(threshold)?;
+    }
+    if let Some(currency) = config.currency {
+        store_currency(currency)?;
+    }
+    if let Some(admin) = config.admin {
+        if admin == Principal::anonymous() {
+            return Err(Error::InvalidInput {
+                msg: "The anonymous principal cannot be the admin.".to_string(),
+            });
+        }
+        store_admin(admin);
+    }
+    Ok(())
+}
+
+// Seed the configuration at install time; installing without an argument
+// keeps every default
+#[ic_cdk::init]
+fn init(config: Option<InitConfig>) {
+    if let Err(error) = apply_init_config(config.unwrap_or_default()) {
+        ic_cdk::trap(&format!("Invalid init config: {:?}", error));
+    }
+}
+
+// Configuration and records live in stable memory and survive upgrades as is;
+// an upgrade argument may override individual values. Records stored under an
+// older schema are migrated lazily rather than here: StoredEnergyUsage fills in
+// the fields they lack and cuts over-long device types whenever they are read,
+// and the current shape is written back the next time a record is stored.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(config: Option<InitConfig>) {
+    if let Err(error) = apply_init_config(config.unwrap_or_default()) {
+        ic_cdk::trap(&format!("Invalid upgrade config: {:?}", error));
+    }
+}
+
+// Convert a day count since the Unix epoch to a (year, month, day) civil date
+// in UTC (Howard Hinnant's days-to-civil algorithm)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+// Convert a (year, month, day) civil date in UTC to days since the Unix epoch
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Calendar (year, month) of a timestamp in UTC
+fn year_month(timestamp: u64) -> (u64, u64) {
+    let (year, month, _) = civil_from_days(timestamp / NANOS_PER_DAY);
+    (year, month)
+}
+
+// Start of a calendar month, in nanoseconds since the epoch
+fn month_start_ns((year, month): (u64, u64)) -> u64 {
+    days_from_civil(year, month, 1) * NANOS_PER_DAY
+}
+
+// Half-open [start, end) nanosecond range covering a calendar month
+fn month_bounds(year_month: (u64, u64)) -> (u64, u64) {
+    let (year, month) = year_month;
+    let next = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    (month_start_ns(year_month), month_start_ns(next))
+}
+
+// Records whose timestamp falls within the current calendar month
+fn current_month_records() -> Vec<EnergyUsage> {
+    let (start, end) = month_bounds(year_month(now()));
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| (start..end).contains(&usage.timestamp))
+            .map(|(_, usage)| usage)
+            .collect()
+    })
+}
+
+// This month's readings in the High recommendation tier, largest first
+#[ic_cdk::query]
+fn high_tier_this_month() -> Vec<EnergyUsage> {
+    let mut records: Vec<EnergyUsage> = current_month_records()
+        .into_iter()
+        .filter(|usage| classify_usage(usage.usage_kwh) == RecommendationLevel::High)
+        .collect();
+    records.sort_by(|a, b| b.usage_kwh.total_cmp(&a.usage_kwh));
+    records
+}
+
+// Delete several records at once, returning the IDs that were actually
+// removed. IDs that don't exist are skipped, as are records the caller does
+// not own unless the caller is the admin; the anonymous principal owns none.
+#[ic_cdk::update]
+fn delete_many(ids: Vec<u64>) -> Vec<u64> {
+    let caller = caller();
+    let is_admin = get_admin() == Some(caller);
+    let may_delete = |usage: &EnergyUsage| {
+        is_admin || (caller != Principal::anonymous() && usage.owner == caller)
+    };
+    STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        ids.into_iter()
+            .filter(|id| {
+                let allowed = storage.get(id).is_some_and(|usage| may_delete(&usage));
+                allowed && storage.remove(id).is_some()
+            })
+            .collect()
+    })
+}
+
+// Renewable share of all recorded usage as a percentage, weighted by each
+// record's usage rather than averaging the per-record percentages
+#[ic_cdk::query]
+fn overall_renewable_percent() -> f64 {
+    let (renewable, nonrenewable) = renewable_breakdown();
+    let total = renewable + nonrenewable;
+    if total == 0.0 {
+        0.0
+    } else {
+        renewable / total * 100.0
+    }
+}
+
+// Report groups of record IDs that share a device type and timestamp,
+// without changing anything; only groups with more than one record appear
+#[ic_cdk::query]
+fn find_duplicates() -> Vec<Vec<u64>> {
+    let mut groups: BTreeMap<(String, u64), Vec<u64>> = BTreeMap::new();
+    for usage in all_records() {
+        groups
+            .entry((usage.device_type, usage.timestamp))
+            .or_default()
+            .push(usage.id);
+    }
+    groups.into_values().filter(|ids| ids.len() > 1).collect()
+}
+
+// Retrieve the number of decimal places reported cost and CO2 figures use
+#[ic_cdk::query]
+fn get_decimal_places() -> u8 {
+    DECIMAL_PLACES.with(|cell| *cell.borrow().get())
+}
+
+// Set the number of decimal places (0-6) for reported cost and CO2 figures.
+// Stored readings keep full precision; only derived figures are rounded.
+#[ic_cdk::update]
+fn set_decimal_places(decimal_places: u8) -> Result<(), Error> {
+    ensure_admin()?;
+    if decimal_places > MAX_DECIMAL_PLACES {
+        return Err(Error::InvalidInput {
+            msg: format!("Decimal places must be between 0 and {}.", MAX_DECIMAL_PLACES),
+        });
+    }
+    DECIMAL_PLACES
+        .with(|cell| cell.borrow_mut().set(decimal_places))
+        .expect("Cannot store the decimal places");
+    Ok(())
+}
+
+// Round a value to the given number of decimal places
+fn round_to(value: f64, decimal_places: u8) -> f64 {
+    let factor = 10f64.powi(i32::from(decimal_places));
+    (value * factor).round() / factor
+}
+
+// Round a derived cost or CO2 figure to the configured decimal places
+fn round_reported(value: f64) -> f64 {
+    round_to(value, get_decimal_places())
+}
+
+// Estimate the cost of all recorded usage at the given rate, or at the
+// stored tariff when no rate is passed; records with a cost override count
+// at their override
+#[ic_cdk::query]
+fn estimate_cost(rate_per_kwh: Option<f64>) -> Result<f64, Error> {
+    let rate = rate_per_kwh.unwrap_or_else(get_tariff);
+    validate_rate(rate)?;
+    let cost: f64 = all_records().iter().map(|usage| record_cost_at(usage, rate)).sum();
+    Ok(round_reported(cost))
+}
+
+// Sum of usage recorded within the last `days` days up to now; 0 days sums
+// nothing
+#[ic_cdk::query]
+fn usage_last_days(days: u64) -> f64 {
+    if days == 0 {
+        return 0.0;
+    }
+    let end = now();
+    let start = end.saturating_sub(days.saturating_mul(NANOS_PER_DAY));
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| (start..=end).contains(&usage.timestamp))
+            .map(|(_, usage)| usage.usage_kwh)
+            .sum()
+    })
+}
+
+// Total usage per device type, keyed by device type
+fn totals_by_device() -> BTreeMap<String, f64> {
+    STORAGE.with(|s| {
+        let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+        for (_, usage) in s.borrow().iter() {
+            *totals.entry(usage.device_type).or_default() += usage.usage_kwh;
+        }
+        totals
+    })
+}
+
+// Plain-text summary of the stored data, suitable for emailing. The output
+// depends only on the stored records, so it is stable for a given dataset.
+#[ic_cdk::query]
+fn text_report() -> String {
+    let records = all_records();
+    if records.is_empty() {
+        return "Energy usage report\nNo energy usage records.\n".to_string();
+    }
+
+    let total: f64 = records.iter().map(|usage| usage.usage_kwh).sum();
+    // Ties go to the alphabetically first device type
+    let (top_device, top_total) = totals_by_device()
+        .into_iter()
+        .fold((String::new(), f64::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+    let highest = records.iter().fold(&records[0], |best, usage| {
+        if usage.usage_kwh > best.usage_kwh {
+            usage
+        } else {
+            best
+        }
+    });
+
+    format!(
+        "Energy usage report\n\
+         Total usage: {:.2} kWh\n\
+         Records: {}\n\
+         Top device: {} ({:.2} kWh)\n\
+         Average per reading: {:.2} kWh\n\
+         Highest reading: {:.2} kWh ({}, record {})\n",
+        total,
+        records.len(),
+        top_device,
+        top_total,
+        total / records.len() as f64,
+        highest.usage_kwh,
+        highest.device_type,
+        highest.id,
+    )
+}
+
+// Device type with the highest average usage per reading, i.e. the one to
+// focus on first; ties go to the alphabetically first device type
+#[ic_cdk::query]
+fn worst_device() -> Result<String, Error> {
+    stats_by_device()
+        .into_iter()
+        .fold(None, |worst: Option<(String, f64)>, (device_type, stats)| match worst {
+            Some(worst) if worst.1 >= stats.mean => Some(worst),
+            _ => Some((device_type, stats.mean)),
+        })
+        .map(|(device_type, _)| device_type)
+        .ok_or(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        })
+}
+
+// The calendar month before the given one
+fn previous_month((year, month): (u64, u64)) -> (u64, u64) {
+    if month == 1 {
+        (year - 1, 12)
+    } else {
+        (year, month - 1)
+    }
+}
+
+// Total usage per calendar (year, month)
+fn monthly_totals() -> BTreeMap<(u64, u64), f64> {
+    STORAGE.with(|s| {
+        let mut totals: BTreeMap<(u64, u64), f64> = BTreeMap::new();
+        for (_, usage) in s.borrow().iter() {
+            *totals.entry(year_month(usage.timestamp)).or_default() += usage.usage_kwh;
+        }
+        totals
+    })
+}
+
+// Forecast next month's usage as the average of the last three complete
+// calendar months. Months before the first recorded one are not counted, so
+// fewer than three are used when the history is short; at least one complete
+// month of history is required.
+#[ic_cdk::query]
+fn forecast_next_month() -> Result<f64, Error> {
+    let totals = monthly_totals();
+    let first_month = totals.keys().next().copied();
+    let mut month = year_month(now());
+    let mut history = Vec::new();
+    for _ in 0..3 {
+        month = previous_month(month);
+        if first_month.is_none_or(|first| month < first) {
+            break;
+        }
+        history.push(totals.get(&month).copied().unwrap_or(0.0));
+    }
+
+    if history.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "At least one complete month of data is required for a forecast.".to_string(),
+        });
+    }
+    Ok(history.iter().sum::<f64>() / history.len() as f64)
+}
+
+// Whether new records are currently accepted
+#[ic_cdk::query]
+fn is_accepting_inserts() -> bool {
+    ACCEPTING_INSERTS.with(|cell| *cell.borrow().get() != 0)
+}
+
+// Pause or resume new inserts, e.g. during maintenance; reads stay available
+#[ic_cdk::update]
+fn set_accepting_inserts(accepting: bool) -> Result<(), Error> {
+    ensure_admin()?;
+    ACCEPTING_INSERTS
+        .with(|cell| cell.borrow_mut().set(u8::from(accepting)))
+        .expect("Cannot store the insert flag");
+    Ok(())
+}
+
+// Guard for every update that creates new records
+fn ensure_accepting_inserts() -> Result<(), Error> {
+    if !is_accepting_inserts() {
+        return Err(Error::Paused {
+            msg: "New energy usage records are not being accepted right now.".to_string(),
+        });
+    }
+    if record_count() >= MAX_RECORDS {
+        return Err(Error::MemoryFull {
+            msg: format!("Storage is full at {} records.", MAX_RECORDS),
+        });
+    }
+    Ok(())
+}
+
+// UTC hour of the day (0-23) of a timestamp
+fn hour_of_day(timestamp: u64) -> u64 {
+    timestamp % NANOS_PER_DAY / NANOS_PER_HOUR
+}
+
+// Total usage per UTC time-of-day segment, always listing all four segments:
+// Night (0-6), Morning (6-12), Afternoon (12-18) and Evening (18-24)
+#[ic_cdk::query]
+fn usage_by_daypart() -> Vec<(String, f64)> {
+    let mut totals = [0.0; 4];
+    for usage in all_records() {
+        totals[(hour_of_day(usage.timestamp) / 6) as usize] += usage.usage_kwh;
+    }
+    ["Night", "Morning", "Afternoon", "Evening"]
+        .iter()
+        .zip(totals)
+        .map(|(label, total)| (label.to_string(), total))
+        .collect()
+}
+
+// Records timestamped within [start_ns, end_ns]
+fn records_between(start_ns: u64, end_ns: u64) -> Vec<EnergyUsage> {
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, usage)| (start_ns..=end_ns).contains(&usage.timestamp))
+            .map(|(_, usage)| usage)
+            .collect()
+    })
+}
+
+// Reject time ranges whose start comes after their end
+fn validate_range(start_ns: u64, end_ns: u64) -> Result<(), Error> {
+    if start_ns > end_ns {
+        return Err(Error::InvalidInput {
+            msg: "Range start must not be after its end.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Estimate the bill for a billing period: usage within [start_ns, end_ns]
+// times the rate, plus a fixed charge. Records with a cost override count at
+// their override.
+#[ic_cdk::query]
+fn estimate_bill(
+    start_ns: u64,
+    end_ns: u64,
+    rate_per_kwh: f64,
+    fixed_charge: f64,
+) -> Result<f64, Error> {
+    validate_range(start_ns, end_ns)?;
+    validate_rate(rate_per_kwh)?;
+    if !fixed_charge.is_finite() || fixed_charge < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Fixed charge must be a non-negative number.".to_string(),
+        });
+    }
+    let usage_cost: f64 = records_between(start_ns, end_ns)
+        .iter()
+        .map(|usage| record_cost_at(usage, rate_per_kwh))
+        .sum();
+    Ok(round_reported(usage_cost + fixed_charge))
+}
+
+// Cost of total usage under block pricing. Each tier is (upper_bound_kwh,
+// rate) and charges the usage between the previous bound and its own; the
+// final tier always covers the remainder, so its bound may be 0 or f64::MAX.
+#[ic_cdk::query]
+fn estimate_tiered_cost(tiers: Vec<(f64, f64)>) -> Result<f64, Error> {
+    if tiers.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "At least one pricing tier is required.".to_string(),
+        });
+    }
+    let mut previous_bound = 0.0;
+    for (index, &(bound, rate)) in tiers.iter().enumerate() {
+        validate_rate(rate)?;
+        let is_final = index == tiers.len() - 1;
+        let open_ended = is_final && (bound == 0.0 || bound == f64::MAX);
+        if !open_ended && (!bound.is_finite() || bound <= previous_bound) {
+            return Err(Error::InvalidInput {
+                msg: "Tier bounds must be positive and strictly ascending.".to_string(),
+            });
+        }
+        previous_bound = bound;
+    }
+
+    let total = total_usage_kwh().max(0.0);
+    let mut cost = 0.0;
+    let mut lower = 0.0;
+    for (index, &(bound, rate)) in tiers.iter().enumerate() {
+        let upper = if index == tiers.len() - 1 { f64::INFINITY } else { bound };
+        cost += (total.min(upper) - lower).max(0.0) * rate;
+        lower = upper;
+    }
+    Ok(round_reported(cost))
+}
+
+// Per-day totals for every day from start_ns to end_ns inclusive, as
+// (day_since_epoch, total_kwh) pairs; days without readings report 0 so the
+// series has no gaps
+#[ic_cdk::query]
+fn daily_series(start_ns: u64, end_ns: u64) -> Result<Vec<(u64, f64)>, Error> {
+    validate_range(start_ns, end_ns)?;
+    let (first_day, last_day) = (start_ns / NANOS_PER_DAY, end_ns / NANOS_PER_DAY);
+    if last_day - first_day >= MAX_SERIES_DAYS {
+        return Err(Error::InvalidInput {
+            msg: format!("A series can span at most {} days.", MAX_SERIES_DAYS),
+        });
+    }
+
+    let totals = totals_by_bucket(NANOS_PER_DAY);
+    Ok((first_day..=last_day)
+        .map(|day| (day, totals.get(&day).copied().unwrap_or(0.0)))
+        .collect())
+}
+
+// Store expected per-reading usage baselines for several device types at once,
+// returning how many were stored. The whole batch is rejected if any entry is
+// invalid.
+#[ic_cdk::update]
+fn set_device_baselines(baselines: Vec<(String, f64)>) -> Result<u64, Error> {
+    ensure_admin()?;
+    let mut validated = Vec::with_capacity(baselines.len());
+    for (device_type, baseline_kwh) in baselines {
+        if !baseline_kwh.is_finite() || baseline_kwh <= 0.0 {
+            return Err(Error::InvalidInput {
+                msg: format!("Baseline for '{}' must be greater than 0.", device_type),
+            });
+        }
+        validated.push((device_key(device_type)?, baseline_kwh));
+    }
+
+    let stored = validated.len() as u64;
+    DEVICE_BASELINES.with(|map| {
+        let mut map = map.borrow_mut();
+        for (key, baseline_kwh) in validated {
+            map.insert(key, baseline_kwh);
+        }
+    });
+    Ok(stored)
+}
+
+// List the configured device baselines, by device type
+#[ic_cdk::query]
+fn get_device_baselines() -> Vec<(String, f64)> {
+    DEVICE_BASELINES.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(key, baseline_kwh)| (key.0, baseline_kwh))
+            .collect()
+    })
+}
+
+// Number of records in each recommendation tier, as (low, moderate, high);
+// a cheaper alternative to records_by_tier when only counts are needed
+#[ic_cdk::query]
+fn recommendation_distribution() -> (u64, u64, u64) {
+    STORAGE.with(|s| {
+        s.borrow().iter().fold((0, 0, 0), |(low, moderate, high), (_, usage)| {
+            match classify_usage(usage.usage_kwh) {
+                RecommendationLevel::Low => (low + 1, moderate, high),
+                RecommendationLevel::Moderate => (low, moderate + 1, high),
+                RecommendationLevel::High => (low, moderate, high + 1),
+            }
+        })
+    })
+}
+
+// Devices whose usage this month exceeds last month's by more than the given
+// factor, as (device_type, last_month_kwh, this_month_kwh). Only devices with
+// readings in both months are compared.
+#[ic_cdk::query]
+fn device_month_jumps(increase_factor: f64) -> Vec<(String, f64, f64)> {
+    let this_month = year_month(now());
+    let last_month = previous_month(this_month);
+    let mut totals: BTreeMap<String, (Option<f64>, Option<f64>)> = BTreeMap::new();
+    for usage in all_records() {
+        let month = year_month(usage.timestamp);
+        let entry = totals.entry(usage.device_type).or_default();
+        if month == last_month {
+            *entry.0.get_or_insert(0.0) += usage.usage_kwh;
+        } else if month == this_month {
+            *entry.1.get_or_insert(0.0) += usage.usage_kwh;
+        }
+    }
+
+    totals
+        .into_iter()
+        .filter_map(|(device_type, totals)| match totals {
+            (Some(last), Some(this)) if this > last * increase_factor => {
+                Some((device_type, last, this))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Earliest and latest record timestamps, found in a single scan
+#[ic_cdk::query]
+fn time_span() -> Result<(u64, u64), Error> {
+    STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .fold(None, |span: Option<(u64, u64)>, (_, usage)| {
+                let (min, max) = span.unwrap_or((usage.timestamp, usage.timestamp));
+                Some((min.min(usage.timestamp), max.max(usage.timestamp)))
+            })
+            .ok_or(Error::NotFound {
+                msg: "No energy usage records found.".to_string(),
+            })
+    })
+}
+
+// Exchange the device types of two records in place. Recommendations depend
+// only on usage, so they are left unchanged.
+#[ic_cdk::update]
+fn swap_device_types(id_a: u64, id_b: u64) -> Result<(), Error> {
+    let mut record_a = get_energy_usage(id_a)?;
+    let mut record_b = get_energy_usage(id_b)?;
+    std::mem::swap(&mut record_a.device_type, &mut record_b.device_type);
+    do_insert(&record_a)?;
+    do_insert(&record_b)
+}
+
+// Shannon entropy (base 2) of each device type's share of total usage;
+// higher means consumption is spread more evenly. One device or no data
+// gives 0. Devices with a non-positive net total carry no share.
+#[ic_cdk::query]
+fn device_usage_entropy() -> f64 {
+    let totals: Vec<f64> = totals_by_device().into_values().filter(|total| *total > 0.0).collect();
+    let sum: f64 = totals.iter().sum();
+    if totals.len() < 2 {
+        return 0.0;
+    }
+    totals
+        .iter()
+        .map(|total| {
+            let share = total / sum;
+            -share * share.log2()
+        })
+        .sum()
+}
+
+// Reject percentages outside 0-100
+fn validate_percent(percent: f64) -> Result<(), Error> {
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(Error::InvalidInput {
+            msg: "Percent must be between 0 and 100.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Cost saved per device type by cutting its usage by reduction_percent at
+// the given rate, biggest opportunity first
+#[ic_cdk::query]
+fn savings_opportunities(
+    reduction_percent: f64,
+    rate_per_kwh: f64,
+) -> Result<Vec<(String, f64)>, Error> {
+    validate_percent(reduction_percent)?;
+    validate_rate(rate_per_kwh)?;
+    let mut savings: Vec<(String, f64)> = totals_by_device()
+        .into_iter()
+        .map(|(device_type, total)| {
+            let saved = total.max(0.0) * reduction_percent / 100.0 * rate_per_kwh;
+            (device_type, round_reported(saved))
+        })
+        .collect();
+    savings.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(savings)
+}
+
+// Compute the current per-day totals and store them in the snapshot map,
+// returning how many days were written. Snapshots are point-in-time: later
+// inserts or deletes only show up after the next snapshot. Days are only
+// ever added or refreshed, never removed, so history survives pruning.
+#[ic_cdk::update]
+fn snapshot_daily_totals() -> u64 {
+    let totals = totals_by_bucket(NANOS_PER_DAY);
+    let days = totals.len() as u64;
+    DAILY_SNAPSHOT.with(|snapshot| {
+        let mut snapshot = snapshot.borrow_mut();
+        for (day, total) in totals {
+            snapshot.insert(day, total);
+        }
+    });
+    days
+}
+
+// Read the precomputed per-day totals as (day_since_epoch, total_kwh) pairs
+#[ic_cdk::query]
+fn get_daily_snapshot() -> Vec<(u64, f64)> {
+    DAILY_SNAPSHOT.with(|snapshot| snapshot.borrow().iter().collect())
+}
+
+// Linearly interpolated percentile (0-100) of an ascending, non-empty slice
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let rank = percent / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+// Records whose usage falls outside the 1.5 * IQR fences around Q1 and Q3,
+// sorted by usage. Fewer than four records yields no outliers.
+#[ic_cdk::query]
+fn iqr_outliers() -> Vec<EnergyUsage> {
+    let records = all_records();
+    if records.len() < 4 {
+        return Vec::new();
+    }
+    let mut values: Vec<f64> = records.iter().map(|usage| usage.usage_kwh).collect();
+    values.sort_by(f64::total_cmp);
+    let q1 = percentile(&values, 25.0);
+    let q3 = percentile(&values, 75.0);
+    let fence = 1.5 * (q3 - q1);
+    let mut outliers: Vec<EnergyUsage> = records
+        .into_iter()
+        .filter(|usage| usage.usage_kwh < q1 - fence || usage.usage_kwh > q3 + fence)
+        .collect();
+    outliers.sort_by(|a, b| a.usage_kwh.total_cmp(&b.usage_kwh));
+    outliers
+}
+
+// Retrieve the number of decimal places incoming readings are rounded to
+#[ic_cdk::query]
+fn get_input_precision() -> u8 {
+    INPUT_PRECISION.with(|cell| *cell.borrow().get())
+}
+
+// Set the number of decimal places (0-6) add_energy_usage rounds incoming
+// readings to before storing them; 6 effectively disables rounding
+#[ic_cdk::update]
+fn set_input_precision(decimal_places: u8) -> Result<(), Error> {
+    ensure_admin()?;
+    if decimal_places > MAX_DECIMAL_PLACES {
+        return Err(Error::InvalidInput {
+            msg: format!("Input precision must be between 0 and {}.", MAX_DECIMAL_PLACES),
+        });
+    }
+    INPUT_PRECISION
+        .with(|cell| cell.borrow_mut().set(decimal_places))
+        .expect("Cannot store the input precision");
+    Ok(())
+}
+
+// Most recent reading timestamp per device type, keyed by device type
+fn last_seen_by_device() -> BTreeMap<String, u64> {
+    STORAGE.with(|s| {
+        let mut last_seen: BTreeMap<String, u64> = BTreeMap::new();
+        for (_, usage) in s.borrow().iter() {
+            let seen = last_seen.entry(usage.device_type).or_default();
+            *seen = (*seen).max(usage.timestamp);
+        }
+        last_seen
+    })
+}
+
+// Device types whose most recent reading is more than `days` days old
+#[ic_cdk::query]
+fn inactive_devices(days: u64) -> Vec<String> {
+    let cutoff = now().saturating_sub(days.saturating_mul(NANOS_PER_DAY));
+    last_seen_by_device()
+        .into_iter()
+        .filter(|(_, last_seen)| *last_seen < cutoff)
+        .map(|(device_type, _)| device_type)
+        .collect()
+}
+
+// Cost of a single record at the stored tariff
+fn record_cost(usage: &EnergyUsage) -> f64 {
+    record_cost_at(usage, get_tariff())
+}
+
+// Cost of a single record: its override when set, otherwise its usage at the
+// given rate
+fn record_cost_at(usage: &EnergyUsage, rate_per_kwh: f64) -> f64 {
+    usage.cost_override.unwrap_or(usage.usage_kwh * rate_per_kwh)
+}
+
+// The (cheapest, most expensive) records by cost at the stored tariff
+#[ic_cdk::query]
+fn cost_extremes() -> Result<(EnergyUsage, EnergyUsage), Error> {
+    let records = all_records();
+    let by_cost = |a: &&EnergyUsage, b: &&EnergyUsage| record_cost(a).total_cmp(&record_cost(b));
+    match (records.iter().min_by(by_cost), records.iter().max_by(by_cost)) {
+        (Some(cheapest), Some(priciest)) => Ok((cheapest.clone(), priciest.clone())),
+        _ => Err(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        }),
+    }
+}
+
+// Reclassify every non-adjustment record against percentiles of the stored
+// usage instead of the kWh thresholds: above high_pct is High, above
+// moderate_pct is Moderate, the rest Low. Returns how many records changed.
+// Admin only, as it rewrites every user's records.
+#[ic_cdk::update]
+fn apply_percentile_recommendations(moderate_pct: f64, high_pct: f64) -> Result<u64, Error> {
+    ensure_admin()?;
+    validate_percent(moderate_pct)?;
+    validate_percent(high_pct)?;
+    if moderate_pct >= high_pct {
+        return Err(Error::InvalidInput {
+            msg: "Moderate percentile must be below the high percentile.".to_string(),
+        });
+    }
+    let records: Vec<EnergyUsage> =
+        all_records().into_iter().filter(|usage| !usage.is_adjustment).collect();
+    if records.is_empty() {
+        return Ok(0);
+    }
+    let mut values: Vec<f64> = records.iter().map(|usage| usage.usage_kwh).collect();
+    values.sort_by(f64::total_cmp);
+    let moderate = percentile(&values, moderate_pct);
+    let high = percentile(&values, high_pct);
+
+    let mut changed = 0;
+    for mut usage in records {
+        let level = if usage.usage_kwh > high {
+            RecommendationLevel::High
+        } else if usage.usage_kwh > moderate {
+            RecommendationLevel::Moderate
+        } else {
+            RecommendationLevel::Low
+        };
+        let recommendation = Some(recommendation_for_level(level));
+        if usage.recommendation != recommendation {
+            usage.recommendation = recommendation;
+            do_insert(&usage)?;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+// Register a device type's rated power in watts, replacing any earlier value
+#[ic_cdk::update]
+fn set_device_rated_watts(device_type: String, rated_watts: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    if !rated_watts.is_finite() || rated_watts < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Rated watts must be a non-negative number.".to_string(),
+        });
+    }
+    let key = device_key(device_type)?;
+    DEVICE_RATED_WATTS.with(|map| map.borrow_mut().insert(key, rated_watts));
+    Ok(())
+}
+
+// Retrieve a device type's registered rated power in watts
+#[ic_cdk::query]
+fn get_device_rated_watts(device_type: String) -> Option<f64> {
+    DEVICE_RATED_WATTS.with(|map| map.borrow().get(&DeviceKey(device_type)))
+}
+
+// Hours the record's device would have run at its rated power to use the
+// recorded energy; the device is linked to the record by device type
+#[ic_cdk::query]
+fn estimate_runtime_hours(record_id: u64) -> Result<f64, Error> {
+    let usage = get_energy_usage(record_id)?;
+    let rated_watts = get_device_rated_watts(usage.device_type.clone()).ok_or_else(|| {
+        Error::NotFound {
+            msg: format!("No device registered for '{}'.", usage.device_type),
+        }
+    })?;
+    if rated_watts == 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!("Device '{}' has a rated power of 0 W.", usage.device_type),
+        });
+    }
+    Ok(usage.usage_kwh * 1_000.0 / rated_watts)
+}
+
+// Usage-weighted renewable share per calendar month, as
+// (year, month, avg_renewable_percent) in chronological order; months whose
+// net usage is zero report 0
+#[ic_cdk::query]
+fn monthly_renewable_trend() -> Vec<(u64, u64, f64)> {
+    let mut renewable: BTreeMap<(u64, u64), f64> = BTreeMap::new();
+    for usage in all_records() {
+        *renewable.entry(year_month(usage.timestamp)).or_default() += renewable_kwh(&usage);
+    }
+    monthly_totals()
+        .into_iter()
+        .map(|((year, month), total)| {
+            let share = renewable.get(&(year, month)).copied().unwrap_or_default();
+            let percent = if total == 0.0 { 0.0 } else { share / total * 100.0 };
+            (year, month, percent)
+        })
+        .collect()
+}
+
+// Number of stored records
+fn record_count() -> u64 {
+    STORAGE.with(|s| s.borrow().len())
+}
+
+// Retrieve the record count at which inserts report near_capacity
+#[ic_cdk::query]
+fn get_warn_at_records() -> u64 {
+    WARN_AT_RECORDS.with(|cell| *cell.borrow().get())
+}
+
+// Set the record count at which inserts start reporting near_capacity, so
+// clients can nudge users to export or prune before the hard cap
+#[ic_cdk::update]
+fn set_warn_at_records(warn_at_records: u64) -> Result<(), Error> {
+    ensure_admin()?;
+    if warn_at_records > MAX_RECORDS {
+        return Err(Error::InvalidInput {
+            msg: format!("Warning threshold cannot exceed {} records.", MAX_RECORDS),
+        });
+    }
+    WARN_AT_RECORDS
+        .with(|cell| cell.borrow_mut().set(warn_at_records))
+        .expect("Cannot store the record warning threshold");
+    Ok(())
+}
+
+// Records matching every supplied filter, in timestamp order: an exact device
+// type, a timestamp at or after start_ns and usage of at least min_kwh. None
+// skips that filter, so passing no filters returns every record. The matches
+// are truncated to fit a response.
+#[ic_cdk::query]
+fn query_records(
+    device_type: Option<String>,
+    start_ns: Option<u64>,
+    min_kwh: Option<f64>,
+) -> EnergyUsagePage {
+    let matching: Vec<EnergyUsage> = records_by_time()
+        .into_iter()
+        .filter(|usage| device_type.as_ref().is_none_or(|device| usage.device_type == *device))
+        .filter(|usage| start_ns.is_none_or(|start| usage.timestamp >= start))
+        .filter(|usage| min_kwh.is_none_or(|min| usage.usage_kwh >= min))
+        .collect();
+    let (records, truncated) = truncate_to_fit(matching, |usage| usage.to_bytes().len());
+    EnergyUsagePage { records, truncated }
+}
+
+// (device_type, expected_baseline, actual_average) for every device with both
+// a configured baseline and readings, furthest above its baseline first
+#[ic_cdk::query]
+fn baseline_variance() -> Vec<(String, f64, f64)> {
+    let stats = stats_by_device();
+    let mut variance: Vec<(String, f64, f64)> = get_device_baselines()
+        .into_iter()
+        .filter_map(|(device_type, baseline)| {
+            let mean = stats.get(&device_type)?.mean;
+            Some((device_type, baseline, mean))
+        })
+        .collect();
+    variance.sort_by(|a, b| (b.2 / b.1).total_cmp(&(a.2 / a.1)));
+    variance
+}
+
+// Total usage in each of `buckets` equal slices of the recorded time span, for
+// small inline charts; always `buckets` long, all zeros when nothing is stored
+#[ic_cdk::query]
+fn sparkline(buckets: u64) -> Result<Vec<f64>, Error> {
+    Ok(readings_by_span_bucket(buckets)?
+        .iter()
+        .map(|readings| readings.iter().sum())
+        .collect())
+}
+
+// Usage values split into `buckets` equal slices of the recorded time span,
+// in chronological order of the slices
+fn readings_by_span_bucket(buckets: u64) -> Result<Vec<Vec<f64>>, Error> {
+    if !(1..=MAX_SPARKLINE_BUCKETS).contains(&buckets) {
+        return Err(Error::InvalidInput {
+            msg: format!("Buckets must be between 1 and {}.", MAX_SPARKLINE_BUCKETS),
+        });
+    }
+    let mut readings = vec![Vec::new(); buckets as usize];
+    let Ok((first, last)) = time_span() else {
+        return Ok(readings);
+    };
+    // The span is widened by 1 ns so the latest reading lands in the last bucket
+    let span = u128::from(last - first) + 1;
+    for usage in all_records() {
+        let index = u128::from(usage.timestamp - first) * u128::from(buckets) / span;
+        readings[index as usize].push(usage.usage_kwh);
+    }
+    Ok(readings)
+}
+
+// Gini coefficient of the per-record usage values: 0 when every reading is the
+// same, approaching 1 as usage concentrates in a few readings. Fewer than two
+// records, or a non-positive total, gives 0.
+#[ic_cdk::query]
+fn usage_gini() -> f64 {
+    let mut values: Vec<f64> = all_records().iter().map(|usage| usage.usage_kwh).collect();
+    let total: f64 = values.iter().sum();
+    if values.len() < 2 || total <= 0.0 {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    let n = values.len() as f64;
+    let weighted: f64 = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (2.0 * (i + 1) as f64 - n - 1.0) * value)
+        .sum();
+    weighted / (n * total)
+}
+
+// Retrieve the monthly usage budget in kWh; 0 means no budget is set
+#[ic_cdk::query]
+fn get_monthly_budget() -> f64 {
+    MONTHLY_BUDGET_KWH.with(|cell| *cell.borrow().get())
+}
+
+// Set the monthly usage budget in kWh; 0 clears the budget
+#[ic_cdk::update]
+fn set_monthly_budget(budget_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    if !budget_kwh.is_finite() || budget_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Monthly budget must be a non-negative number.".to_string(),
+        });
+    }
+    MONTHLY_BUDGET_KWH
+        .with(|cell| cell.borrow_mut().set(budget_kwh))
+        .expect("Cannot store the monthly budget");
+    Ok(())
+}
+
+// Whether adding a reading of usage_kwh would push this calendar month's
+// total over the monthly budget; nothing is stored. Always false without a
+// budget.
+#[ic_cdk::query]
+fn would_exceed_budget(usage_kwh: f64) -> bool {
+    let budget = get_monthly_budget();
+    if budget <= 0.0 {
+        return false;
+    }
+    let month_total: f64 = current_month_records().iter().map(|usage| usage.usage_kwh).sum();
+    month_total + usage_kwh > budget
+}
+
+// (device_type, last_seen_ns) pairs, most recently used first. Device types
+// differing only in case are merged and shown with the spelling of their
+// latest reading.
+#[ic_cdk::query]
+fn devices_by_recency() -> Vec<(String, u64)> {
+    let mut latest: BTreeMap<String, (String, u64)> = BTreeMap::new();
+    for usage in all_records() {
+        let entry = latest
+            .entry(usage.device_type.to_lowercase())
+            .or_insert_with(|| (usage.device_type.clone(), usage.timestamp));
+        if usage.timestamp > entry.1 {
+            *entry = (usage.device_type, usage.timestamp);
+        }
+    }
+    let mut devices: Vec<(String, u64)> = latest.into_values().collect();
+    devices.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    devices
+}
+
+// Record a main-meter reading made up of several sub-meter circuits. The
+// components are kept on the record and their sum becomes its usage, which
+// is validated and advised on like any other reading.
+#[ic_cdk::update]
+fn add_composite_usage(device_type: String, components: Vec<f64>) -> Result<EnergyUsage, Error> {
+    ensure_accepting_inserts()?;
+    if components.is_empty() || components.len() > MAX_COMPONENTS {
+        return Err(Error::InvalidInput {
+            msg: format!("A composite reading needs 1 to {} components.", MAX_COMPONENTS),
+        });
+    }
+    if components.iter().any(|kwh| !kwh.is_finite() || *kwh < 0.0) {
+        return Err(Error::InvalidInput {
+            msg: "Components must be non-negative numbers.".to_string(),
+        });
+    }
+    let payload = EnergyUsagePayload {
+        usage_kwh: components.iter().sum(),
+        device_type,
+        ..Default::default()
+    };
+    let usage_kwh = validate_usage_payload(&payload)?;
+
+    let composite = EnergyUsage {
+        id: next_id(),
+        usage_kwh,
+        timestamp: now(),
+        device_type: payload.device_type,
+        recommendation: Some(generate_recommendation(usage_kwh)),
+        owner: caller(),
+        components,
+        ..Default::default()
+    };
+    do_insert(&composite)?;
+    Ok(composite)
+}
+
+// Each device type's share of this calendar month's usage as
+// (device_type, percent), largest first; empty when the month has no usage
+#[ic_cdk::query]
+fn current_month_device_shares() -> Vec<(String, f64)> {
+    let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+    for usage in current_month_records() {
+        *totals.entry(usage.device_type).or_default() += usage.usage_kwh;
+    }
+    let month_total: f64 = totals.values().sum();
+    if month_total <= 0.0 {
+        return Vec::new();
+    }
+    let mut shares: Vec<(String, f64)> = totals
+        .into_iter()
+        .map(|(device_type, total)| (device_type, total / month_total * 100.0))
+        .collect();
+    shares.sort_by(|a, b| b.1.total_cmp(&a.1));
+    shares
+}
+
+// Reject empty tags and tags longer than MAX_TAG_LEN
+fn validate_tag(tag: &str) -> Result<(), Error> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("Tags must be between 1 and {} bytes long.", MAX_TAG_LEN),
+        });
+    }
+    Ok(())
+}
+
+// Add a tag to every record of the device type that lacks it, returning how
+// many records changed. Nothing is tagged if any of them already carries
+// MAX_TAGS tags.
+#[ic_cdk::update]
+fn tag_matching(device_type: String, tag: String) -> Result<u64, Error> {
+    if device_type.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Device type cannot be empty.".to_string(),
+        });
+    }
+    validate_tag(&tag)?;
+    let untagged: Vec<EnergyUsage> = all_records()
+        .into_iter()
+        .filter(|usage| usage.device_type == device_type && !usage.tags.contains(&tag))
+        .collect();
+    if untagged.iter().any(|usage| usage.tags.len() >= MAX_TAGS) {
+        return Err(Error::InvalidInput {
+            msg: format!("Records can carry at most {} tags.", MAX_TAGS),
+        });
+    }
+
+    for mut usage in untagged.iter().cloned() {
+        usage.tags.push(tag.clone());
+        do_insert(&usage)?;
+    }
+    Ok(untagged.len() as u64)
+}
+
+// Replace a record's tags, e.g. to make room once it carries MAX_TAGS; an
+// empty list clears them. Repeated tags are kept once.
+#[ic_cdk::update]
+fn set_tags(id: u64, tags: Vec<String>) -> Result<EnergyUsage, Error> {
+    let mut unique: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        validate_tag(&tag)?;
+        if !unique.contains(&tag) {
+            unique.push(tag);
+        }
+    }
+    if unique.len() > MAX_TAGS {
+        return Err(Error::InvalidInput {
+            msg: format!("Records can carry at most {} tags.", MAX_TAGS),
+        });
+    }
+    let mut usage = get_energy_usage(id)?;
+    usage.tags = unique;
+    do_insert(&usage)?;
+    Ok(usage)
+}
+
+// Whether a UTC hour falls in the [start, end) window, which wraps past
+// midnight when start > end
+fn in_hour_window(hour: u64, start: u8, end: u8) -> bool {
+    let (start, end) = (u64::from(start), u64::from(end));
+    if start <= end {
+        (start..end).contains(&hour)
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+// Cost saved if shift_percent of the usage recorded in the UTC peak window
+// [peak_start, peak_end) were billed at the off-peak rate instead. The window
+// may wrap past midnight; a cheaper peak rate gives a negative saving.
+#[ic_cdk::query]
+fn load_shift_savings(
+    peak_start: u8,
+    peak_end: u8,
+    peak_rate: f64,
+    offpeak_rate: f64,
+    shift_percent: f64,
+) -> Result<f64, Error> {
+    if peak_start > 23 || peak_end > 23 || peak_start == peak_end {
+        return Err(Error::InvalidInput {
+            msg: "Peak hours must be two different hours between 0 and 23.".to_string(),
+        });
+    }
+    validate_rate(peak_rate)?;
+    validate_rate(offpeak_rate)?;
+    validate_percent(shift_percent)?;
+    let peak_kwh: f64 = all_records()
+        .iter()
+        .filter(|usage| in_hour_window(hour_of_day(usage.timestamp), peak_start, peak_end))
+        .map(|usage| usage.usage_kwh)
+        .sum();
+    let shifted_kwh = peak_kwh * shift_percent / 100.0;
+    Ok(round_reported(shifted_kwh * (peak_rate - offpeak_rate)))
+}
+
+// Records timestamped after the current time, e.g. from clock skew or bad
+// imports, latest first
+#[ic_cdk::query]
+fn future_dated_records() -> Vec<EnergyUsage> {
+    let current = now();
+    let mut future: Vec<EnergyUsage> =
+        all_records().into_iter().filter(|usage| usage.timestamp > current).collect();
+    future.sort_by_key(|usage| std::cmp::Reverse(usage.timestamp));
+    future
+}
+
+// (id, usage_kwh / device_baseline) for every record whose device type has a
+// configured baseline, in ID order; 1.0 is on-baseline and above 1.0 is over
+#[ic_cdk::query]
+fn normalized_readings() -> Vec<(u64, f64)> {
+    DEVICE_BASELINES.with(|baselines| {
+        let baselines = baselines.borrow();
+        all_records()
+            .into_iter()
+            .filter_map(|usage| {
+                let baseline = baselines.get(&DeviceKey(usage.device_type))?;
+                Some((usage.id, usage.usage_kwh / baseline))
+            })
+            .collect()
+    })
+}
+
+// The (day_since_epoch, total_kwh) of the day with the highest total usage;
+// the earliest such day wins a tie
+#[ic_cdk::query]
+fn busiest_day() -> Result<(u64, f64), Error> {
+    totals_by_bucket(NANOS_PER_DAY)
+        .into_iter()
+        .fold(None, |busiest: Option<(u64, f64)>, (day, total)| match busiest {
+            Some(busiest) if busiest.1 >= total => Some(busiest),
+            _ => Some((day, total)),
+        })
+        .ok_or(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        })
+}
+
+// Energy sources must be non-empty and fit the bound used for stored records
+fn validate_energy_source(energy_source: &str) -> Result<(), Error> {
+    if energy_source.is_empty() || energy_source.len() > MAX_ENERGY_SOURCE_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Energy source must be between 1 and {} bytes long.",
+                MAX_ENERGY_SOURCE_LEN
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Set the carbon intensity CO2 queries use for readings from an energy source
+#[ic_cdk::update]
+fn set_emission_factor(energy_source: String, g_co2_per_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    validate_energy_source(&energy_source)?;
+    validate_grid_intensity(g_co2_per_kwh)?;
+    EMISSION_FACTORS.with(|map| map.borrow_mut().insert(DeviceKey(energy_source), g_co2_per_kwh));
+    Ok(())
+}
+
+// List the configured emission factors, by energy source
+#[ic_cdk::query]
+fn get_emission_factors() -> Vec<(String, f64)> {
+    EMISSION_FACTORS.with(|map| map.borrow().iter().map(|(key, factor)| (key.0, factor)).collect())
+}
+
+// Median reading per device type as (device_type, median_kwh), highest
+// first; even-sized groups average their two middle readings
+#[ic_cdk::query]
+fn median_usage_by_device() -> Vec<(String, f64)> {
+    let mut readings: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for usage in all_records() {
+        readings.entry(usage.device_type).or_default().push(usage.usage_kwh);
+    }
+    let mut medians: Vec<(String, f64)> = readings
+        .into_iter()
+        .map(|(device_type, mut values)| {
+            values.sort_by(f64::total_cmp);
+            (device_type, percentile(&values, 50.0))
+        })
+        .collect();
+    medians.sort_by(|a, b| b.1.total_cmp(&a.1));
+    medians
+}
+
+// CO2 in kilograms that the renewable share of every reading would have
+// emitted had it come from the grid at the given intensity; this is the share
+// total_co2_kg treats as emission-free
+#[ic_cdk::query]
+fn renewable_co2_avoided(grid_intensity_g_per_kwh: f64) -> Result<f64, Error> {
+    validate_grid_intensity(grid_intensity_g_per_kwh)?;
+    let renewable: f64 = all_records().iter().map(renewable_kwh).sum();
+    Ok(round_reported(renewable * grid_intensity_g_per_kwh / 1_000.0))
+}
+
+// Total usage of each of `days` consecutive UTC days starting with the day
+// containing start_ns, zero-filled, for calendar heatmaps
+#[ic_cdk::query]
+fn daily_heatmap(start_ns: u64, days: u64) -> Result<Vec<f64>, Error> {
+    if !(1..=MAX_SERIES_DAYS).contains(&days) {
+        return Err(Error::InvalidInput {
+            msg: format!("Days must be between 1 and {}.", MAX_SERIES_DAYS),
+        });
+    }
+    let first_day = start_ns / NANOS_PER_DAY;
+    let totals = totals_by_bucket(NANOS_PER_DAY);
+    Ok((first_day..first_day + days)
+        .map(|day| totals.get(&day).copied().unwrap_or(0.0))
+        .collect())
+}
+
+// Cost of the recorded usage under the current and a proposed tariff
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct TariffComparison {
+    total_usage_kwh: f64,   // Total recorded usage
+    current_cost: f64,      // Cost of the records at the current rate
+    proposed_cost: f64,     // Cost of the records at the proposed rate
+    annual_difference: f64, // Projected yearly proposed minus current cost
+}
+
+// Compare the cost of the recorded usage under two rates. Records with a cost
+// override cost the same under both. The annual difference is projected from
+// the data span like projected_annual_usage, so it needs at least a day of
+// data; a negative difference means the proposed rate is cheaper.
+#[ic_cdk::query]
+fn compare_tariffs(current_rate: f64, proposed_rate: f64) -> Result<TariffComparison, Error> {
+    validate_rate(current_rate)?;
+    validate_rate(proposed_rate)?;
+    let span_days = projection_span_days()?;
+    let records = all_records();
+    let cost_at = |rate| records.iter().map(|usage| record_cost_at(usage, rate)).sum::<f64>();
+    let (current_cost, proposed_cost) = (cost_at(current_rate), cost_at(proposed_rate));
+    Ok(TariffComparison {
+        total_usage_kwh: records.iter().map(|usage| usage.usage_kwh).sum(),
+        current_cost: round_reported(current_cost),
+        proposed_cost: round_reported(proposed_cost),
+        annual_difference: round_reported((proposed_cost - current_cost) / span_days * 365.0),
+    })
+}
+
+// Each distinct stored recommendation and how many records carry it, most
+// common first; records without a recommendation are not counted
+#[ic_cdk::query]
+fn recommendation_message_counts() -> Vec<(String, u64)> {
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    for usage in all_records() {
+        if let Some(recommendation) = usage.recommendation {
+            *counts.entry(recommendation).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts
+}
+
+// Set or clear the actual cost of a reading billed at a special rate; cost
+// figures use the override instead of usage times the rate
+#[ic_cdk::update]
+fn set_cost_override(id: u64, cost: Option<f64>) -> Result<EnergyUsage, Error> {
+    if cost.is_some_and(|cost| !cost.is_finite() || cost < 0.0) {
+        return Err(Error::InvalidInput {
+            msg: "Cost override must be a non-negative number.".to_string(),
+        });
+    }
+    let mut usage = get_energy_usage(id)?;
+    usage.cost_override = cost;
+    do_insert(&usage)?;
+    Ok(usage)
+}
+
+// Canonical billing total: each record's cost override when set, otherwise
+// its usage at the stored tariff
+#[ic_cdk::query]
+fn total_cost() -> f64 {
+    round_reported(all_records().iter().map(record_cost).sum())
+}
+
+// Records paired with their cost, cut short when they would not fit in a
+// single reply
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CostedRecordsPage {
+    records: Vec<(EnergyUsage, f64)>, // (record, cost), most expensive first
+    truncated: bool,                  // True when some records were left out
+}
+
+// Every record paired with its cost (override or stored tariff), most
+// expensive first, truncated to fit a response
+#[ic_cdk::query]
+fn records_by_cost() -> CostedRecordsPage {
+    let mut costed: Vec<(EnergyUsage, f64)> = all_records()
+        .into_iter()
+        .map(|usage| {
+            let cost = round_reported(record_cost(&usage));
+            (usage, cost)
+        })
+        .collect();
+    costed.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let (records, truncated) = truncate_to_fit(costed, |(usage, _)| usage.to_bytes().len() + 8);
+    CostedRecordsPage { records, truncated }
+}
+
+// kWh a more efficient replacement would have saved over the device type's
+// recorded usage, where the replacement uses (1 - new_efficiency_factor) of
+// the current energy; the factor must be in 0-1
+#[ic_cdk::query]
+fn replacement_savings(device_type: String, new_efficiency_factor: f64) -> Result<f64, Error> {
+    if !(0.0..=1.0).contains(&new_efficiency_factor) {
+        return Err(Error::InvalidInput {
+            msg: "Efficiency factor must be between 0 and 1.".to_string(),
+        });
+    }
+    let total = totals_by_device().get(&device_type).copied().ok_or_else(|| {
+        Error::NotFound {
+            msg: format!("No readings found for device type '{}'.", device_type),
+        }
+    })?;
+    Ok(total * new_efficiency_factor)
+}
+
+// Summary of the readings falling in one slice of the time span
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct BucketStat {
+    min_kwh: f64, // Smallest reading, 0 when empty
+    max_kwh: f64, // Largest reading, 0 when empty
+    avg_kwh: f64, // Mean reading, 0 when empty
+    count: u64,   // Number of readings
+}
+
+// Min, max, average and count of the readings in each of `buckets` equal
+// slices of the recorded time span, for candlestick-style charts
+#[ic_cdk::query]
+fn bucketed_stats(buckets: u64) -> Result<Vec<BucketStat>, Error> {
+    Ok(readings_by_span_bucket(buckets)?
+        .into_iter()
+        .map(|readings| {
+            if readings.is_empty() {
+                return BucketStat::default();
+            }
+            let count = readings.len() as u64;
+            BucketStat {
+                min_kwh: readings.iter().copied().fold(f64::INFINITY, f64::min),
+                max_kwh: readings.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                avg_kwh: readings.iter().sum::<f64>() / count as f64,
+                count,
+            }
+        })
+        .collect())
+}
+
+// Retrieve the (moderate, high) kWh thresholds used to pick recommendations
+#[ic_cdk::query]
+fn get_recommendation_thresholds() -> (f64, f64) {
+    (
+        MODERATE_THRESHOLD_KWH.with(|cell| *cell.borrow().get()),
+        HIGH_THRESHOLD_KWH.with(|cell| *cell.borrow().get()),
+    )
+}
+
+// Set the kWh values above which new readings get the Moderate and High
+// recommendations. Stored recommendations are not rewritten; see
+// stale_recommendations.
+#[ic_cdk::update]
+fn set_recommendation_thresholds(moderate_kwh: f64, high_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    if !moderate_kwh.is_finite() || !high_kwh.is_finite() || moderate_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Thresholds must be non-negative numbers.".to_string(),
+        });
+    }
+    if moderate_kwh >= high_kwh {
+        return Err(Error::InvalidInput {
+            msg: "Moderate threshold must be below the high threshold.".to_string(),
+        });
+    }
+    MODERATE_THRESHOLD_KWH
+        .with(|cell| cell.borrow_mut().set(moderate_kwh))
+        .expect("Cannot store the moderate threshold");
+    HIGH_THRESHOLD_KWH
+        .with(|cell| cell.borrow_mut().set(high_kwh))
+        .expect("Cannot store the high threshold");
+    Ok(())
+}
+
+// IDs of non-adjustment records whose stored recommendation differs from the
+// one the current thresholds would generate, i.e. what regenerating every
+// recommendation would change
+#[ic_cdk::query]
+fn stale_recommendations() -> Vec<u64> {
+    all_records()
+        .into_iter()
+        .filter(|usage| !usage.is_adjustment)
+        .filter(|usage| usage.recommendation != Some(generate_recommendation(usage.usage_kwh)))
+        .map(|usage| usage.id)
+        .collect()
+}
+
+// Link a meter photo or receipt to a record; an empty URL removes the link
+#[ic_cdk::update]
+fn set_attachment(id: u64, url: String) -> Result<EnergyUsage, Error> {
+    let attachment_url = validate_attachment_url(url)?;
+    let mut usage = get_energy_usage(id)?;
+    usage.attachment_url = attachment_url;
+    do_insert(&usage)?;
+    Ok(usage)
+}
+
+// Accept bounded http(s) URLs without whitespace; an empty URL means no link
+fn validate_attachment_url(url: String) -> Result<Option<String>, Error> {
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    let well_formed = rest.is_some_and(|rest| !rest.is_empty())
+        && !url.chars().any(char::is_whitespace);
+    if !well_formed || url.len() > MAX_ATTACHMENT_URL_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Attachment must be an http(s) URL of at most {} bytes.",
+                MAX_ATTACHMENT_URL_LEN
+            ),
+        });
+    }
+    Ok(Some(url))
+}
+
+// For each of the last `days` UTC days up to today, the total usage of the
+// 30 days ending that day, as (day_since_epoch, trailing_total_kwh) pairs
+#[ic_cdk::query]
+fn rolling_30day_series(days: u64) -> Result<Vec<(u64, f64)>, Error> {
+    if !(1..=MAX_SERIES_DAYS).contains(&days) {
+        return Err(Error::InvalidInput {
+            msg: format!("Days must be between 1 and {}.", MAX_SERIES_DAYS),
+        });
+    }
+    let today = now() / NANOS_PER_DAY;
+    let first_day = (today + 1).saturating_sub(days);
+    let totals = totals_by_bucket(NANOS_PER_DAY);
+    Ok((first_day..=today)
+        .map(|day| {
+            let window_start = (day + 1).saturating_sub(30);
+            (day, totals.range(window_start..=day).map(|(_, total)| total).sum())
+        })
+        .collect())
+}
+
+// Retrieve the configured household size; 0 means it has not been set
+#[ic_cdk::query]
+fn get_household_size() -> u32 {
+    HOUSEHOLD_SIZE.with(|cell| *cell.borrow().get())
+}
+
+// Set the number of people in the household for per-capita figures
+#[ic_cdk::update]
+fn set_household_size(household_size: u32) -> Result<(), Error> {
+    ensure_admin()?;
+    HOUSEHOLD_SIZE
+        .with(|cell| cell.borrow_mut().set(household_size))
+        .expect("Cannot store the household size");
+    Ok(())
+}
+
+// Total usage per household member
+#[ic_cdk::query]
+fn per_capita_usage() -> Result<f64, Error> {
+    let household_size = get_household_size();
+    if household_size == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Household size has not been set.".to_string(),
+        });
+    }
+    Ok(total_usage_kwh() / f64::from(household_size))
+}
+
+// The single most impactful thing to act on
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct ActionSuggestion {
+    device_type: String, // Device type costing the most
+    total_cost: f64,     // Its total cost at the requested rate
+    message: String,     // Advice on what to do about it
+}
+
+// Suggest acting on the device type with the highest total cost at the given
+// rate (cost overrides included), or None when nothing is recorded
+#[ic_cdk::query]
+fn top_action(rate_per_kwh: f64) -> Result<Option<ActionSuggestion>, Error> {
+    validate_rate(rate_per_kwh)?;
+    let mut costs: BTreeMap<String, f64> = BTreeMap::new();
+    for usage in all_records() {
+        let cost = record_cost_at(&usage, rate_per_kwh);
+        *costs.entry(usage.device_type).or_default() += cost;
+    }
+    let top = costs
+        .into_iter()
+        .fold(None, |top: Option<(String, f64)>, (device_type, cost)| match top {
+            Some(top) if top.1 >= cost => Some(top),
+            _ => Some((device_type, cost)),
+        });
+    Ok(top.map(|(device_type, cost)| {
+        let total_cost = round_reported(cost);
+        ActionSuggestion {
+            message: format!(
+                "{} is your biggest energy cost at {} {}. Consider using it less or replacing it with a more efficient model.",
+                device_type,
+                total_cost,
+                get_currency()
+            ),
+            device_type,
+            total_cost,
+        }
+    }))
+}
+
+// Renumber records with contiguous IDs from 0 in timestamp order and reset
+// the ID counter to follow them, returning how many records got a new ID.
+// Any ID held outside the canister no longer refers to the same record, so
+// only the admin may do this.
+#[ic_cdk::update]
+fn compact_ids() -> Result<u64, Error> {
+    ensure_admin()?;
+    let records = records_by_time();
+    let renumbered = records
+        .iter()
+        .enumerate()
+        .filter(|(new_id, usage)| *new_id as u64 != usage.id)
+        .count() as u64;
+    STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        for usage in &records {
+            storage.remove(&usage.id);
+        }
+        for (new_id, mut usage) in records.iter().cloned().enumerate() {
+            usage.id = new_id as u64;
+            storage.insert(usage.id, usage);
+        }
+    });
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(records.len() as u64))
+        .expect("Cannot reset ID counter");
+    Ok(renumbered)
+}
+
+// Split total usage into (base_load_kwh, variable_load_kwh). The lowest daily
+// total over the days with readings is taken as the constant daily base load,
+// and everything above it on each of those days counts as variable.
+#[ic_cdk::query]
+fn base_vs_variable_load() -> Result<(f64, f64), Error> {
+    let daily = totals_by_bucket(NANOS_PER_DAY);
+    let Some(base_per_day) = daily.values().copied().reduce(f64::min) else {
+        return Err(Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        });
+    };
+    let total: f64 = daily.values().sum();
+    let base = base_per_day * daily.len() as f64;
+    Ok((base, total - base))
+}
+
+// Header row of the CSV exports
+const CSV_HEADER: &str = "id,timestamp,device_type,usage_kwh,recommendation";
+
+// Quote a CSV field when it contains a delimiter, quote or line break,
+// doubling any embedded quotes
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// One CSV row for a record, matching CSV_HEADER
+fn csv_row(usage: &EnergyUsage) -> String {
+    format!(
+        "{},{},{},{},{}",
+        usage.id,
+        usage.timestamp,
+        csv_escape(&usage.device_type),
+        usage.usage_kwh,
+        csv_escape(usage.recommendation.as_deref().unwrap_or_default())
+    )
+}
+
+// CSV of the records whose device type matches case-insensitively, in
+// timestamp order; just the header when nothing matches
+#[ic_cdk::query]
+fn export_device_csv(device_type: String) -> String {
+    let device_type = device_type.to_lowercase();
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for usage in records_by_time() {
+        if usage.device_type.to_lowercase() == device_type {
+            csv.push_str(&csv_row(&usage));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+// Demand charge on the single highest reading at the given rate
+#[ic_cdk::query]
+fn demand_charge(demand_rate_per_kwh: f64) -> Result<f64, Error> {
+    validate_rate(demand_rate_per_kwh)?;
+    let peak = all_records().iter().map(|usage| usage.usage_kwh).reduce(f64::max).ok_or(
+        Error::NotFound {
+            msg: "No energy usage records found.".to_string(),
+        },
+    )?;
+    Ok(round_reported(peak * demand_rate_per_kwh))
+}
+
+// Move every record of device type `from` to `into`, returning how many
+// moved. A baseline configured for `from` moves to `into` unless `into`
+// already has one. Admin only, as baselines are configuration.
+#[ic_cdk::update]
+fn merge_device_types(from: String, into: String) -> Result<u64, Error> {
+    ensure_admin()?;
+    validate_device_type(&into)?;
+    if from == into {
+        return Err(Error::InvalidInput {
+            msg: "Cannot merge a device type into itself.".to_string(),
+        });
+    }
+    let moved: Vec<EnergyUsage> =
+        all_records().into_iter().filter(|usage| usage.device_type == from).collect();
+    for mut usage in moved.iter().cloned() {
+        usage.device_type = into.clone();
+        do_insert(&usage)?;
+    }
+
+    DEVICE_BASELINES.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(baseline) = map.remove(&DeviceKey(from)) {
+            let into = DeviceKey(into);
+            if !map.contains_key(&into) {
+                map.insert(into, baseline);
+            }
+        }
+    });
+    Ok(moved.len() as u64)
+}
+
+// Share of consumption met by renewables, as a percentage clamped to 0-100.
+// This is the usage-weighted overall_renewable_percent; the clamp keeps
+// negative adjustments from pushing it out of range.
+#[ic_cdk::query]
+fn self_sufficiency_percent() -> f64 {
+    overall_renewable_percent().clamp(0.0, 100.0)
+}
+
+// Mean reading and its standard error as (mean, standard_error), using the
+// sample standard deviation over sqrt(n); needs at least two records
+#[ic_cdk::query]
+fn usage_mean_with_error() -> Result<(f64, f64), Error> {
+    let mut stats = RunningStats::default();
+    for usage in all_records() {
+        stats.push(usage.usage_kwh);
+    }
+    if stats.count < 2 {
+        return Err(Error::NotFound {
+            msg: "At least two energy usage records are required.".to_string(),
+        });
+    }
+    let standard_error = (stats.sample_variance() / stats.count as f64).sqrt();
+    Ok((stats.mean, standard_error))
+}
+
+// Save every record into the backup cell, replacing any earlier backup, and
+// return how many were saved; admin only
+#[ic_cdk::update]
+fn create_backup() -> Result<u64, Error> {
+    ensure_admin()?;
+    let records = all_records();
+    let mut blob = vec![BACKUP_VERSION];
+    blob.extend(Encode!(&records).expect("Cannot encode the backup"));
+    BACKUP
+        .with(|cell| cell.borrow_mut().set(blob))
+        .expect("Cannot store the backup");
+    Ok(records.len() as u64)
+}
+
+// Replace every stored record with the contents of the backup and return how
+// many were restored. The ID counter never moves backwards, so IDs handed
+// out after the backup are not reused. Admin only, as this discards every
+// current record.
+#[ic_cdk::update]
+fn restore_backup() -> Result<u64, Error> {
+    ensure_admin()?;
+    let blob = BACKUP.with(|cell| cell.borrow().get().clone());
+    let Some((&version, encoded)) = blob.split_first() else {
+        return Err(Error::NotFound {
+            msg: "No backup has been created.".to_string(),
+        });
+    };
+    if version != BACKUP_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!("Unsupported backup version {}.", version),
+        });
+    }
+    // Decoded like stored records, so backups taken before a field was added
+    // still restore
+    let stored = Decode!(encoded, Vec<StoredEnergyUsage>).map_err(|err| Error::InvalidInput {
+        msg: format!("Backup is corrupted: {}", err),
+    })?;
+    let records: Vec<EnergyUsage> = stored.into_iter().map(EnergyUsage::from).collect();
+
+    STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        let ids: Vec<u64> = storage.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            storage.remove(&id);
+        }
+        for usage in &records {
+            storage.insert(usage.id, usage.clone());
+        }
+    });
+    let next_free = records.iter().map(|usage| usage.id + 1).max().unwrap_or(0);
+    ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value.max(next_free))
+        })
+        .expect("Cannot update ID counter");
+    Ok(records.len() as u64)
+}
+
+// Total usage of the records carrying each requested tag, as (tag, total_kwh)
+// pairs in the requested order; a record counts toward every tag it carries
+#[ic_cdk::query]
+fn usage_by_tags(tags: Vec<String>) -> Vec<(String, f64)> {
+    let records = all_records();
+    tags.into_iter()
+        .map(|tag| {
+            let total = records
+                .iter()
+                .filter(|usage| usage.tags.contains(&tag))
+                .map(|usage| usage.usage_kwh)
+                .sum();
+            (tag, total)
+        })
+        .collect()
+}
+
+// Yearly cost if the average daily cost over the recorded span continues,
+// counting records at their cost override when set; needs a day of data
+#[ic_cdk::query]
+fn projected_annual_cost(rate_per_kwh: f64) -> Result<f64, Error> {
+    validate_rate(rate_per_kwh)?;
+    let span_days = projection_span_days()?;
+    let cost: f64 = all_records().iter().map(|usage| record_cost_at(usage, rate_per_kwh)).sum();
+    Ok(round_reported(cost / span_days * 365.0))
+}
+
+// Records whose cost (override or stored tariff), rounded like reported
+// costs, falls within [min_cost, max_cost], cheapest first
+#[ic_cdk::query]
+fn records_in_cost_range(min_cost: f64, max_cost: f64) -> Result<Vec<EnergyUsage>, Error> {
+    if !min_cost.is_finite() || !max_cost.is_finite() || min_cost < 0.0 || min_cost > max_cost {
+        return Err(Error::InvalidInput {
+            msg: "Cost range must be non-negative with min_cost <= max_cost.".to_string(),
+        });
+    }
+    let mut matching: Vec<(EnergyUsage, f64)> = all_records()
+        .into_iter()
+        .map(|usage| {
+            let cost = round_reported(record_cost(&usage));
+            (usage, cost)
+        })
+        .filter(|(_, cost)| (min_cost..=max_cost).contains(cost))
+        .collect();
+    matching.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Ok(matching.into_iter().map(|(usage, _)| usage).collect())
+}
+
+// Total usage expressed as hours of running an appliance of the given wattage
+#[ic_cdk::query]
+fn equivalent_runtime(appliance_watts: u32) -> Result<f64, Error> {
+    if appliance_watts == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Appliance wattage must be greater than 0.".to_string(),
+        });
+    }
+    Ok(total_usage_kwh() * 1_000.0 / f64::from(appliance_watts))
+}
+
+// Month-to-date usage against the same stretch of the previous month
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct MtdComparison {
+    this_month_kwh: f64, // Usage from the start of this month up to now
+    last_month_kwh: f64, // Usage over the same days of last month
+    percent_change: f64, // Change from last month, 0 when last month had none
+}
+
+// Compare this month's usage so far with last month's up to the same day of
+// the month. When last month is shorter, the comparison day is clamped to its
+// final day, so e.g. March 30 compares against all of February.
+#[ic_cdk::query]
+fn mtd_comparison() -> MtdComparison {
+    let current = now();
+    let this_month = year_month(current);
+    let (this_start, _) = month_bounds(this_month);
+    let (last_start, last_end) = month_bounds(previous_month(this_month));
+    let day_of_month = (current - this_start) / NANOS_PER_DAY + 1;
+    let last_cutoff = (last_start + day_of_month * NANOS_PER_DAY).min(last_end);
+
+    let this_month_kwh = records_between(this_start, current)
+        .iter()
+        .map(|usage| usage.usage_kwh)
+        .sum();
+    let last_month_kwh: f64 = records_between(last_start, last_cutoff - 1)
+        .iter()
+        .map(|usage| usage.usage_kwh)
+        .sum();
+    let percent_change = if last_month_kwh == 0.0 {
+        0.0
+    } else {
+        (this_month_kwh - last_month_kwh) / last_month_kwh * 100.0
+    };
+    MtdComparison {
+        this_month_kwh,
+        last_month_kwh,
+        percent_change,
+    }
+}
+
+// Header accepted as the first line of a CSV import
+const CSV_IMPORT_HEADER: &str = "timestamp,device_type,usage_kwh";
+
+// One reading in an import batch
+#[derive(Deserialize)]
+struct ImportRow {
+    timestamp: u64,
+    device_type: String,
+    usage_kwh: f64,
+}
+
+// Cut a string to at most max_len bytes without splitting a character
+fn truncate_str(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+// Split one CSV line into fields, honoring the quoting csv_escape produces
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted field.".to_string());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+// Parse a timestamp,device_type,usage_kwh CSV line
+fn parse_csv_row(line: &str) -> Result<ImportRow, String> {
+    let fields = split_csv_line(line)?;
+    let [timestamp, device_type, usage_kwh] = fields.as_slice() else {
+        return Err(format!("Expected 3 fields, found {}.", fields.len()));
+    };
+    Ok(ImportRow {
+        timestamp: timestamp.trim().parse().map_err(|_| "Invalid timestamp.".to_string())?,
+        device_type: device_type.clone(),
+        usage_kwh: usage_kwh.trim().parse().map_err(|_| "Invalid usage.".to_string())?,
+    })
+}
+
+// Validate an import row the way add_energy_usage validates a payload and
+// build its record; the ID is assigned when the record is stored
+fn import_record(row: ImportRow) -> Result<EnergyUsage, Error> {
+    let payload = EnergyUsagePayload {
+        usage_kwh: row.usage_kwh,
+        device_type: row.device_type,
+        ..Default::default()
+    };
+    let usage_kwh = normalized_usage(&payload)?;
+    Ok(EnergyUsage {
+        usage_kwh,
+        timestamp: row.timestamp,
+        device_type: payload.device_type,
+        recommendation: Some(generate_recommendation(usage_kwh)),
+        owner: caller(),
+        ..Default::default()
+    })
+}
+
+// Store a batch of parsed rows. Without quarantine the first invalid row
+// rejects the whole batch; with it, invalid rows are set aside in the
+// quarantine with their reason and the rest are imported. Returns
+// (imported_count, quarantined_count).
+fn import_rows(
+    rows: Vec<(String, Result<ImportRow, String>)>,
+    quarantine_invalid: bool,
+) -> Result<(u64, u64), Error> {
+    ensure_accepting_inserts()?;
+    let mut valid = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, (raw_row, parsed)) in rows.into_iter().enumerate() {
+        let record =
+            parsed.and_then(|row| import_record(row).map_err(|err| err.message().to_string()));
+        match record {
+            Ok(record) => valid.push(record),
+            Err(reason) if quarantine_invalid => invalid.push((raw_row, reason)),
+            Err(reason) => {
+                return Err(Error::InvalidInput {
+                    msg: format!("Row {}: {}", index + 1, reason),
+                })
+            }
+        }
+    }
+    if record_count() + valid.len() as u64 > MAX_RECORDS {
+        return Err(Error::MemoryFull {
+            msg: format!("Importing would exceed the {} record limit.", MAX_RECORDS),
+        });
+    }
+
+    let counts = (valid.len() as u64, invalid.len() as u64);
+    for mut record in valid {
+        record.id = next_id();
+        do_insert(&record)?;
+    }
+    QUARANTINE.with(|map| {
+        let mut map = map.borrow_mut();
+        for (raw_row, reason) in invalid {
+            let key = map.last_key_value().map_or(0, |(key, _)| key + 1);
+            let row = QuarantinedRow {
+                raw_row: truncate_str(&raw_row, MAX_QUARANTINE_ROW_LEN),
+                reason: truncate_str(&reason, MAX_QUARANTINE_REASON_LEN),
+            };
+            map.insert(key, row);
+        }
+    });
+    Ok(counts)
+}
+
+// Import timestamp,device_type,usage_kwh CSV lines, optionally preceded by
+// that header; blank lines are skipped. See import_rows for quarantine.
+#[ic_cdk::update]
+fn import_csv(csv: String, quarantine_invalid: bool) -> Result<(u64, u64), Error> {
+    let rows = csv
+        .lines()
+        .enumerate()
+        .filter(|(index, line)| {
+            !(line.trim().is_empty() || *index == 0 && *line == CSV_IMPORT_HEADER)
+        })
+        .map(|(_, line)| (line.to_string(), parse_csv_row(line)))
+        .collect();
+    import_rows(rows, quarantine_invalid)
+}
+
+// Import a JSON array of {timestamp, device_type, usage_kwh} objects. See
+// import_rows for quarantine; JSON that is not an array is always rejected.
+#[ic_cdk::update]
+fn import_json(json: String, quarantine_invalid: bool) -> Result<(u64, u64), Error> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(&json).map_err(|err| Error::InvalidInput {
+            msg: format!("Import must be a JSON array: {}", err),
+        })?;
+    let rows = values
+        .into_iter()
+        .map(|value| {
+            let raw_row = value.to_string();
+            (raw_row, serde_json::from_value(value).map_err(|err| err.to_string()))
+        })
+        .collect();
+    import_rows(rows, quarantine_invalid)
+}
+
+// Quarantined import rows, cut short when they would not fit in a single reply
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct QuarantinePage {
+    rows: Vec<(String, String)>, // (raw_row, reason), oldest first
+    truncated: bool,             // True when some rows were left out
+}
+
+// Import rows set aside by earlier imports, as (raw_row, reason) pairs,
+// truncated to fit a response
+#[ic_cdk::query]
+fn get_quarantine() -> QuarantinePage {
+    let rows: Vec<(String, String)> = QUARANTINE.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(_, row)| (row.raw_row, row.reason))
+            .collect()
+    });
+    let (rows, truncated) = truncate_to_fit(rows, |(raw_row, reason)| raw_row.len() + reason.len());
+    QuarantinePage { rows, truncated }
+}
+
+// Drop every quarantined import row, returning how many were removed; admin
+// only
+#[ic_cdk::update]
+fn clear_quarantine() -> Result<u64, Error> {
+    ensure_admin()?;
+    Ok(QUARANTINE.with(|map| {
+        let mut map = map.borrow_mut();
+        let keys: Vec<u64> = map.iter().map(|(key, _)| key).collect();
+        for key in &keys {
+            map.remove(key);
+        }
+        keys.len() as u64
+    }))
+}
+
+// Each device type's share of the total cost as (device_type, percent),
+// largest first, pricing records at their override or the given rate; empty
+// when the total cost is zero
+#[ic_cdk::query]
+fn cost_shares_by_device(rate_per_kwh: f64) -> Result<Vec<(String, f64)>, Error> {
+    validate_rate(rate_per_kwh)?;
+    let mut costs: BTreeMap<String, f64> = BTreeMap::new();
+    for usage in all_records() {
+        let cost = record_cost_at(&usage, rate_per_kwh);
+        *costs.entry(usage.device_type).or_default() += cost;
+    }
+    let total: f64 = costs.values().sum();
+    if total <= 0.0 {
+        return Ok(Vec::new());
+    }
+    let mut shares: Vec<(String, f64)> = costs
+        .into_iter()
+        .map(|(device_type, cost)| (device_type, cost / total * 100.0))
+        .collect();
+    shares.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(shares)
+}
+
+// Set a device type's monthly usage quota in kWh; 0 removes the quota
+#[ic_cdk::update]
+fn set_device_quota(device_type: String, quota_kwh: f64) -> Result<(), Error> {
+    ensure_admin()?;
+    if !quota_kwh.is_finite() || quota_kwh < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Device quota must be a non-negative number.".to_string(),
+        });
+    }
+    let key = device_key(device_type)?;
+    DEVICE_QUOTAS.with(|map| {
+        let mut map = map.borrow_mut();
+        if quota_kwh == 0.0 {
+            map.remove(&key);
+        } else {
+            map.insert(key, quota_kwh);
+        }
+    });
+    Ok(())
+}
+
+// A device type's monthly quota against its usage so far this month
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct DeviceQuotaStatus {
+    device_type: String,
+    quota_kwh: f64,
+    month_usage_kwh: f64, // Usage within the current calendar month
+    exceeded: bool,       // Whether this month's usage is over the quota
+}
+
+// Quota status of every device type that has a quota, in device type order
+#[ic_cdk::query]
+fn device_quota_status() -> Vec<DeviceQuotaStatus> {
+    let mut month_totals: BTreeMap<String, f64> = BTreeMap::new();
+    for usage in current_month_records() {
+        *month_totals.entry(usage.device_type).or_default() += usage.usage_kwh;
+    }
+    DEVICE_QUOTAS.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(DeviceKey(device_type), quota_kwh)| {
+                let month_usage_kwh = month_totals.get(&device_type).copied().unwrap_or(0.0);
+                DeviceQuotaStatus {
+                    device_type,
+                    quota_kwh,
+                    month_usage_kwh,
+                    exceeded: month_usage_kwh > quota_kwh,
+                }
+            })
+            .collect()
+    })
+}
+
+// Day of the week of a timestamp in UTC, 0 = Monday through 6 = Sunday; the
+// epoch fell on a Thursday
+fn weekday(timestamp: u64) -> u64 {
+    (timestamp / NANOS_PER_DAY + 3) % 7
+}
+
+// Weekend (Saturday and Sunday, UTC) usage as a percentage of total usage;
+// 0 on empty storage
+#[ic_cdk::query]
+fn weekend_share_percent() -> f64 {
+    let (mut weekend, mut total) = (0.0, 0.0);
+    for usage in all_records() {
+        if weekday(usage.timestamp) >= 5 {
+            weekend += usage.usage_kwh;
+        }
+        total += usage.usage_kwh;
+    }
+    if total == 0.0 {
+        return 0.0;
+    }
+    weekend / total * 100.0
+}
+
+// Daily usage allowance in kWh that keeps the rest of this calendar month
+// within the monthly budget: the remaining budget spread over the days left,
+// counting today. 0 once the budget is used up.
+#[ic_cdk::query]
+fn recommended_daily_target() -> Result<f64, Error> {
+    let budget = get_monthly_budget();
+    if budget <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "No monthly budget is set.".to_string(),
+        });
+    }
+    let now = now();
+    let (_, month_end) = month_bounds(year_month(now));
+    let days_left = (month_end - now / NANOS_PER_DAY * NANOS_PER_DAY) / NANOS_PER_DAY;
+    let month_total: f64 = current_month_records().iter().map(|usage| usage.usage_kwh).sum();
+    Ok((budget - month_total).max(0.0) / days_left as f64)
+}
+
+// Records still owned by the anonymous principal, e.g. ones stored before
+// owners were tracked, oldest first
+#[ic_cdk::query]
+fn ownerless_records() -> Vec<EnergyUsage> {
+    all_records()
+        .into_iter()
+        .filter(|usage| usage.owner == Principal::anonymous())
+        .collect()
+}
+
+// Hand a record over to a new owner; admin only
+#[ic_cdk::update]
+fn assign_owner(id: u64, owner: Principal) -> Result<(), Error> {
+    ensure_admin()?;
+    if owner == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "Records cannot be assigned to the anonymous principal.".to_string(),
+        });
+    }
+    let mut usage = get_energy_usage(id)?;
+    usage.owner = owner;
+    do_insert(&usage)?;
+    Ok(())
+}
+
+// Effective carbon intensity of the stored consumption in g CO2 per kWh: the
+// CO2 of total_co2_kg at the given grid intensity, divided by total usage.
+// 0 on empty storage.
+#[ic_cdk::query]
+fn personal_carbon_intensity(grid_intensity_g_per_kwh: f64) -> Result<f64, Error> {
+    let co2_kg = total_co2_kg(Some(grid_intensity_g_per_kwh))?;
+    let total = total_usage_kwh();
+    if total <= 0.0 {
+        return Ok(0.0);
+    }
+    Ok(round_reported(co2_kg * 1_000.0 / total))
+}
+
+// Readings grouped into the tiers marked out by ascending boundaries, as
+// (label, count, total_kwh). n boundaries give n + 1 tiers, each including
+// its lower boundary: "<5", "5-10", ">=10" for [5.0, 10.0]. Adjustments
+// are not readings and are left out.
+#[ic_cdk::query]
+fn tier_breakdown(boundaries: Vec<f64>) -> Result<Vec<(String, u64, f64)>, Error> {
+    if boundaries.is_empty()
+        || boundaries.iter().any(|bound| !bound.is_finite() || *bound <= 0.0)
+        || boundaries.windows(2).any(|pair| pair[0] >= pair[1])
+    {
+        return Err(Error::InvalidInput {
+            msg: "Tier boundaries must be positive and strictly ascending.".to_string(),
+        });
+    }
+    let mut tiers: Vec<(String, u64, f64)> = Vec::with_capacity(boundaries.len() + 1);
+    tiers.push((format!("<{}", boundaries[0]), 0, 0.0));
+    for pair in boundaries.windows(2) {
+        tiers.push((format!("{}-{}", pair[0], pair[1]), 0, 0.0));
+    }
+    tiers.push((format!(">={}", boundaries[boundaries.len() - 1]), 0, 0.0));
+    for usage in all_records().iter().filter(|usage| !usage.is_adjustment) {
+        let tier = boundaries.partition_point(|bound| *bound <= usage.usage_kwh);
+        tiers[tier].1 += 1;
+        tiers[tier].2 += usage.usage_kwh;
+    }
+    Ok(tiers)
+}
+
+// Energy cost of all records (override or the given rate) plus the price of
+// their emissions, as total_co2_kg estimates them at the given grid intensity
+#[ic_cdk::query]
+fn carbon_adjusted_cost(
+    rate_per_kwh: f64,
+    carbon_price_per_kg: f64,
+    grid_intensity_g_per_kwh: f64,
+) -> Result<f64, Error> {
+    validate_rate(rate_per_kwh)?;
+    validate_grid_intensity(grid_intensity_g_per_kwh)?;
+    if !carbon_price_per_kg.is_finite() || carbon_price_per_kg < 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Carbon price must be a non-negative number.".to_string(),
+        });
+    }
+    let energy_cost: f64 =
+        all_records().iter().map(|usage| record_cost_at(usage, rate_per_kwh)).sum();
+    let co2_kg = total_co2_kg(Some(grid_intensity_g_per_kwh))?;
+    Ok(round_reported(energy_cost + co2_kg * carbon_price_per_kg))
+}
+
+// Longest run of consecutive calendar days (UTC) with total usage under the
+// limit, anywhere between the first and last recorded day. Days without
+// readings inside that span count as zero usage.
+#[ic_cdk::query]
+fn longest_low_usage_streak(daily_limit_kwh: f64) -> Result<u64, Error> {
+    if !daily_limit_kwh.is_finite() || daily_limit_kwh <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: "Daily limit must be a positive number.".to_string(),
+        });
+    }
+    let daily = totals_by_bucket(NANOS_PER_DAY);
+    let (Some((&first, _)), Some((&last, _))) = (daily.first_key_value(), daily.last_key_value())
+    else {
+        return Ok(0);
+    };
+    let (mut longest, mut current) = (0, 0);
+    for day in first..=last {
+        if daily.get(&day).copied().unwrap_or(0.0) < daily_limit_kwh {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    Ok(longest)
+}
+
+// Last calendar month's cost minus this month's so far, pricing records at
+// their override or the given rate; positive means this month is cheaper
+#[ic_cdk::query]
+fn savings_vs_last_month(rate_per_kwh: f64) -> Result<f64, Error> {
+    validate_rate(rate_per_kwh)?;
+    let this_month = year_month(now());
+    let last_month = previous_month(this_month);
+    let (mut last_cost, mut this_cost, mut last_seen) = (0.0, 0.0, false);
+    for usage in all_records() {
+        let month = year_month(usage.timestamp);
+        if month == last_month {
+            last_cost += record_cost_at(&usage, rate_per_kwh);
+            last_seen = true;
+        } else if month == this_month {
+            this_cost += record_cost_at(&usage, rate_per_kwh);
+        }
+    }
+    if !last_seen {
+        return Err(Error::InvalidInput {
+            msg: "Last month has no readings to compare against.".to_string(),
+        });
+    }
+    Ok(round_reported(last_cost - this_cost))
+}
+
+// Define custom error types for the system
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+enum Error {
+    NotFound { msg: String },    // Record not found
+    MemoryFull { msg: String },  // Storage limit reached
+    InvalidInput { msg: String }, // Invalid input provided
+    Unauthorized { msg: String }, // Caller is not allowed to perform the action
+    Paused { msg: String },      // New inserts are temporarily disabled
+}
+
+impl Error {
+    // The human-readable message carried by every variant
+    fn message(&self) -> &str {
+        match self {
+            Error::NotFound { msg }
+            | Error::MemoryFull { msg }
+            | Error::InvalidInput { msg }
+            | Error::Unauthorized { msg }
+            | Error::Paused { msg } => msg,
+        }
+    }
+}
+
+// Export the Candid interface for the canister
+ic_cdk::export_candid!();
+
+// Integration tests to verify functionality
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Move the test clock so records are stamped with the given time
+    fn set_time(timestamp: u64) {
+        TEST_TIME.with(|t| *t.borrow_mut() = timestamp);
+    }
+
+    // Act as the given principal for subsequent calls
+    fn set_caller(principal: Principal) {
+        TEST_CALLER.with(|c| *c.borrow_mut() = principal);
+    }
+
+    // A distinct, non-anonymous principal for tests
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    // Insert a reading as if it had been recorded at the given time
+    fn add_at(timestamp: u64, usage_kwh: f64, device_type: &str) -> EnergyUsage {
+        set_time(timestamp);
+        add_energy_usage(EnergyUsagePayload {
+            usage_kwh,
+            device_type: device_type.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+        .record
+    }
+
+    #[test]
+    fn test_add_and_get_energy_usage() {
+        let payload = EnergyUsagePayload {
+            usage_kwh: 12.0,
+            device_type: "Air Conditioner".to_string(),
+            ..Default::default()
+        };
+        let record = add_energy_usage(payload).unwrap().record;
+        assert_eq!(record.usage_kwh, 12.0);
+        assert!(get_energy_usage(record.id).is_ok());
+    }
+
+    #[test]
+    fn test_delete_energy_usage() {
+        let payload = EnergyUsagePayload {
+            usage_kwh: 5.0,
+            device_type: "Laptop".to_string(),
+            ..Default::default()
+        };
+        let record = add_energy_usage(payload).unwrap().record;
+        assert!(delete_energy_usage(record.id).is_ok());
+        assert!(get_energy_usage(record.id).is_err());
+    }
+
+    #[test]
+    fn test_repair_records_clears_integrity_issues() {
+        set_caller(user(1));
+        let valid = add_energy_usage(EnergyUsagePayload {
+            usage_kwh: 3.0,
+            device_type: "Fridge".to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+        .record;
+        do_insert(&EnergyUsage {
+            id: 100,
+            usage_kwh: -4.0,
+            device_type: "Heater".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let report = integrity_report();
+        assert_eq!(report.negative_usage_ids, vec![100]);
+        assert_eq!(report.missing_recommendation_ids, vec![100]);
+
+        assert_eq!(repair_records().unwrap(), 1);
+        let repaired = get_energy_usage(100).unwrap();
+        assert_eq!(repaired.usage_kwh, 0.0);
+        assert!(repaired.recommendation.is_some());
+        assert_eq!(get_energy_usage(valid.id).unwrap().recommendation, valid.recommendation);
+
+        let report = integrity_report();
+        assert!(report.negative_usage_ids.is_empty());
+        assert!(report.missing_recommendation_ids.is_empty());
+        assert_eq!(repair_records().unwrap(), 0);
+
+        set_caller(user(2));
+        assert!(matches!(repair_records(), Err(Error::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn test_average_daily_usage() {
+        assert!(matches!(average_daily_usage(), Err(Error::NotFound { .. })));
+
+        add_at(0, 2.0, "Laptop");
+        add_at(NANOS_PER_DAY / 2, 4.0, "Laptop");
+        add_at(5 * NANOS_PER_DAY, 6.0, "Oven");
+        assert_eq!(average_daily_usage().unwrap(), 6.0);
+    }
+
+    #[test]
+    fn test_set_recommendation() {
+        let record = add_at(0, 12.0, "Air Conditioner");
+        let updated = set_recommendation(record.id, "Service the filter.".to_string()).unwrap();
+        assert_eq!(updated.recommendation.as_deref(), Some("Service the filter."));
+        assert_eq!(
+            get_energy_usage(record.id).unwrap().recommendation,
+            updated.recommendation
+        );
+
+        let cleared = set_recommendation(record.id, String::new()).unwrap();
+        assert!(cleared.recommendation.is_none());
+
+        let too_long = "x".repeat(MAX_RECOMMENDATION_LEN + 1);
+        assert!(matches!(
+            set_recommendation(record.id, too_long),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            set_recommendation(999, "Hi".to_string()),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    // Store 5000 records at the field limits, more than one response can carry.
+    // They are 1 kWh readings with IDs from 10000, after any a test added.
+    fn fill_past_response_budget() {
+        let device_type = "D".repeat(MAX_DEVICE_TYPE_LEN);
+        let recommendation = "R".repeat(MAX_RECOMMENDATION_LEN);
+        for id in 10_000..15_000 {
+            do_insert(&EnergyUsage {
+                id,
+                usage_kwh: 1.0,
+                device_type: device_type.clone(),
+                recommendation: Some(recommendation.clone()),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_list_and_export_truncate_large_responses() {
+        add_at(0, 1.0, "Lamp");
+        let page = list_all_energy_usage();
+        assert_eq!(page.records.len(), 1);
+        assert!(!page.truncated);
+        let export = export_json();
+        assert!(export.json.contains("\"Lamp\""));
+        assert!(!export.truncated);
+
+        fill_past_response_budget();
+        let page = list_all_energy_usage();
+        assert!(page.truncated);
+        assert!(page.records.len() < 5_000);
+        assert_eq!(page.records[0].device_type, "Lamp");
+        let export = export_json();
+        assert!(export.truncated);
+        assert!(export.json.len() <= MAX_RESPONSE_BYTES);
+    }
+
+    #[test]
+    fn test_device_type_allow_list() {
+        set_caller(user(1));
+        let payload = |device_type: &str| EnergyUsagePayload {
+            usage_kwh: 2.0,
+            device_type: device_type.to_string(),
+            ..Default::default()
+        };
+        assert!(add_energy_usage(payload("Toaster")).is_ok());
+
+        add_allowed_device_type("Fridge".to_string()).unwrap();
+        assert_eq!(list_allowed_device_types(), vec!["Fridge".to_string()]);
+        assert!(add_energy_usage(payload("Fridge")).is_ok());
+        assert!(matches!(
+            add_energy_usage(payload("Toaster")),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        remove_allowed_device_type("Fridge".to_string()).unwrap();
+        assert!(add_energy_usage(payload("Toaster")).is_ok());
+        assert!(matches!(
+            remove_allowed_device_type("Fridge".to_string()),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_usage_variance_by_device() {
+        for usage_kwh in [4.0, 4.0, 4.0] {
+            add_at(0, usage_kwh, "Fridge");
+        }
+        for usage_kwh in [1.0, 9.0, 2.0] {
+            add_at(0, usage_kwh, "Heater");
+        }
+        add_at(0, 7.0, "Kettle");
+
+        let variances = usage_variance_by_device();
+        assert_eq!(variances[0].0, "Heater");
+        assert!(variances[0].1 > 0.0);
+        assert!(variances[1..].iter().all(|(_, variance)| *variance == 0.0));
+    }
+
+    #[test]
+    fn test_sample_ends() {
+        for usage_kwh in 1..=6 {
+            add_at(0, usage_kwh as f64, "Lamp");
+        }
+        let ids = |records: Vec<EnergyUsage>| records.iter().map(|r| r.id).collect::<Vec<_>>();
+
+        let (first, last) = sample_ends(2);
+        assert_eq!(ids(first), vec![0, 1]);
+        assert_eq!(ids(last), vec![4, 5]);
+
+        let (first, last) = sample_ends(4);
+        assert_eq!(ids(first), vec![0, 1, 2, 3]);
+        assert_eq!(ids(last), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_add_adjustment_reduces_total() {
+        set_caller(user(1));
+        add_at(0, 8.0, "Heat Pump");
+        add_at(0, 4.0, "Oven");
+        assert_eq!(total_usage_kwh(), 12.0);
+
+        let credit = add_adjustment("Solar".to_string(), -5.0).unwrap();
+        assert!(credit.is_adjustment);
+        assert_eq!(credit.usage_kwh, -5.0);
+        assert_eq!(total_usage_kwh(), 7.0);
+
+        // Credits are expected to be negative and must survive a repair pass
+        assert!(integrity_report().negative_usage_ids.is_empty());
+        assert_eq!(repair_records().unwrap(), 0);
+        assert!(matches!(
+            add_adjustment("Solar".to_string(), 0.0),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reading_gaps() {
+        add_at(0, 1.0, "Lamp");
+        assert!(reading_gaps(10).is_empty());
+
+        add_at(30, 1.0, "Lamp");
+        add_at(5, 1.0, "Lamp");
+        add_at(100, 1.0, "Lamp");
+        assert_eq!(reading_gaps(25), vec![(30, 100)]);
+        assert_eq!(reading_gaps(20), vec![(5, 30), (30, 100)]);
+    }
+
+    #[test]
+    fn test_alert_threshold_flags_high_readings() {
+        set_caller(user(1));
+        let add = |usage_kwh: f64| {
+            add_energy_usage(EnergyUsagePayload {
+                usage_kwh,
+                device_type: "Dryer".to_string(),
+                ..Default::default()
+            })
+            .unwrap()
+            .alert
+        };
+        assert!(!add(50.0));
+
+        set_alert_threshold(8.0).unwrap();
+        assert_eq!(get_alert_threshold(), 8.0);
+        assert!(!add(8.0));
+        assert!(add(8.5));
+
+        set_alert_threshold(0.0).unwrap();
+        assert!(!add(50.0));
+        assert!(matches!(set_alert_threshold(-1.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_load_factor() {
+        assert!(matches!(load_factor(), Err(Error::NotFound { .. })));
+
+        for usage_kwh in [2.0, 4.0, 12.0] {
+            add_at(0, usage_kwh, "Washer");
+        }
+        assert_eq!(load_factor().unwrap(), 6.0 / 12.0);
+    }
+
+    #[test]
+    fn test_duplicate_record() {
+        let source = add_at(10, 6.5, "Dishwasher");
+        set_time(20);
+        let copy = duplicate_record(source.id).unwrap();
+        assert_ne!(copy.id, source.id);
+        assert_eq!(copy.usage_kwh, source.usage_kwh);
+        assert_eq!(copy.device_type, source.device_type);
+        assert_eq!(copy.timestamp, 20);
+        assert!(get_energy_usage(copy.id).is_ok());
+        assert!(matches!(duplicate_record(999), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_cumulative_usage() {
+        for usage_kwh in [1.0, 2.0, 3.0] {
+            add_at(0, usage_kwh, "Lamp");
+        }
+        assert_eq!(cumulative_usage(), vec![(0, 1.0), (1, 3.0), (2, 6.0)]);
+    }
+
+    #[test]
+    fn test_records_by_tier() {
+        let low = add_at(0, 2.0, "Lamp");
+        let moderate = add_at(0, 7.0, "Oven");
+        let high = add_at(0, 15.0, "Heater");
+        let boundary = add_at(0, 10.0, "Dryer");
+
+        let ids = |records: &[EnergyUsage]| records.iter().map(|r| r.id).collect::<Vec<_>>();
+        let buckets = records_by_tier();
+        assert_eq!(ids(&buckets.low), vec![low.id]);
+        assert_eq!(ids(&buckets.moderate), vec![moderate.id, boundary.id]);
+        assert_eq!(ids(&buckets.high), vec![high.id]);
+        assert!(!buckets.truncated);
+
+        fill_past_response_budget();
+        let buckets = records_by_tier();
+        assert!(buckets.truncated);
+        assert!(buckets.low.len() < 5_000);
+        assert_eq!(ids(&buckets.high), vec![high.id]);
+    }
+
+    #[test]
+    fn test_add_energy_usage_converts_units() {
+        let add = |usage_kwh: f64, unit: Option<EnergyUnit>| {
+            add_energy_usage(EnergyUsagePayload {
+                usage_kwh,
+                device_type: "Meter".to_string(),
+                unit,
+                ..Default::default()
+            })
+        };
+        let wh = add(2_500.0, Some(EnergyUnit::Wh)).unwrap().record;
+        let kwh = add(2.5, None).unwrap().record;
+        let mwh = add(0.0025, Some(EnergyUnit::Mwh)).unwrap().record;
+        assert_eq!(wh.usage_kwh, 2.5);
+        assert_eq!(kwh.usage_kwh, 2.5);
+        assert!((mwh.usage_kwh - 2.5).abs() < 1e-9);
+        assert!(matches!(
+            add(-1.0, Some(EnergyUnit::Wh)),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_projected_annual_usage() {
+        add_at(0, 4.0, "Fridge");
+        add_at(NANOS_PER_DAY / 2, 4.0, "Fridge");
+        assert!(matches!(projected_annual_usage(), Err(Error::InvalidInput { .. })));
+
+        add_at(4 * NANOS_PER_DAY, 4.0, "Fridge");
+        // 12 kWh over four days is a rate of 3 kWh per day
+        assert_eq!(projected_annual_usage().unwrap(), 3.0 * 365.0);
+    }
+
+    #[test]
+    fn test_grid_intensity_config_and_fallback() {
+        set_caller(user(1));
+        assert_eq!(get_grid_intensity(), DEFAULT_GRID_INTENSITY_G_PER_KWH);
+        assert!(matches!(set_grid_intensity(-5.0), Err(Error::InvalidInput { .. })));
+
+        add_at(0, 100.0, "Heater");
+        set_grid_intensity(400.0).unwrap();
+        assert_eq!(get_grid_intensity(), 400.0);
+        assert_eq!(estimate_co2_kg(None).unwrap(), 40.0);
+        assert_eq!(estimate_co2_kg(Some(200.0)).unwrap(), 20.0);
+        assert_eq!(trees_to_offset(None).unwrap(), 2);
+        assert_eq!(trees_to_offset(Some(0.0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_most_frequent_device() {
+        assert!(matches!(most_frequent_device(), Err(Error::NotFound { .. })));
+
+        add_at(0, 1.0, "Kettle");
+        add_at(0, 1.0, "kettle");
+        add_at(0, 1.0, "KETTLE");
+        add_at(0, 1.0, "Toaster");
+        assert_eq!(most_frequent_device().unwrap(), ("Kettle".to_string(), 3));
+
+        add_at(0, 1.0, "Toaster");
+        add_at(0, 1.0, "Toaster");
+        assert_eq!(most_frequent_device().unwrap(), ("Kettle".to_string(), 3));
+    }
+
+    #[test]
+    fn test_set_recommendation_for_tier() {
+        set_caller(user(1));
+        let high_a = add_at(0, 12.0, "Heater");
+        let high_b = add_at(0, 20.0, "Dryer");
+        let moderate = add_at(0, 7.0, "Oven");
+        let low = add_at(0, 1.0, "Lamp");
+
+        let campaign = "Join the winter saving challenge.".to_string();
+        assert_eq!(
+            set_recommendation_for_tier(RecommendationLevel::High, campaign.clone()).unwrap(),
+            2
+        );
+        for id in [high_a.id, high_b.id] {
+            assert_eq!(get_energy_usage(id).unwrap().recommendation, Some(campaign.clone()));
+        }
+        assert_eq!(get_energy_usage(moderate.id).unwrap().recommendation, moderate.recommendation);
+        assert_eq!(get_energy_usage(low.id).unwrap().recommendation, low.recommendation);
+
+        assert_eq!(
+            set_recommendation_for_tier(RecommendationLevel::High, campaign.clone()).unwrap(),
+            0
+        );
+
+        set_caller(user(2));
+        assert!(matches!(
+            set_recommendation_for_tier(RecommendationLevel::Low, campaign),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert_eq!(get_energy_usage(low.id).unwrap().recommendation, low.recommendation);
+    }
+
+    #[test]
+    fn test_id_diagnostics_counts_deleted_ids() {
+        for _ in 0..3 {
+            add_at(0, 1.0, "Lamp");
+        }
+        delete_energy_usage(1).unwrap();
+        assert_eq!(
+            id_diagnostics(),
+            IdDiagnostics {
+                next_id: 3,
+                used_id_count: 2,
+                missing_id_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_weekly_totals() {
+        add_at(3 * NANOS_PER_WEEK + 1, 2.0, "Fridge");
+        add_at(NANOS_PER_WEEK, 1.5, "Fridge");
+        add_at(2 * NANOS_PER_WEEK - 1, 2.5, "Oven");
+        assert_eq!(weekly_totals(), vec![(1, 4.0), (3, 2.0)]);
+    }
+
+    #[test]
+    fn test_validate_payload_stores_nothing() {
+        set_caller(user(1));
+        let payload = |usage_kwh: f64, device_type: &str| EnergyUsagePayload {
+            usage_kwh,
+            device_type: device_type.to_string(),
+            ..Default::default()
+        };
+        assert!(validate_payload(payload(3.0, "Fridge")).is_ok());
+        for invalid in [
+            payload(0.0, "Fridge"),
+            payload(-2.0, "Fridge"),
+            payload(f64::NAN, "Fridge"),
+            payload(f64::INFINITY, "Fridge"),
+            payload(3.0, ""),
+            payload(3.0, &"x".repeat(MAX_DEVICE_TYPE_LEN + 1)),
+        ] {
+            assert!(matches!(validate_payload(invalid), Err(Error::InvalidInput { .. })));
+        }
+
+        add_allowed_device_type("Fridge".to_string()).unwrap();
+        assert!(validate_payload(payload(3.0, "Fridge")).is_ok());
+        assert!(matches!(
+            validate_payload(payload(3.0, "Toaster")),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert_eq!(id_diagnostics().next_id, 0);
+        assert!(all_records().is_empty());
+    }
+
+    #[test]
+    fn test_renewable_breakdown() {
+        let add = |usage_kwh: f64, renewable_percent: f64| {
+            add_energy_usage(EnergyUsagePayload {
+                usage_kwh,
+                device_type: "House".to_string(),
+                renewable_percent: Some(renewable_percent),
+                ..Default::default()
+            })
+        };
+        add(10.0, 0.0).unwrap();
+        add(4.0, 50.0).unwrap();
+        add(6.0, 100.0).unwrap();
+        assert!(matches!(add(1.0, 120.0), Err(Error::InvalidInput { .. })));
+        assert_eq!(renewable_breakdown(), (8.0, 12.0));
+    }
+
+    #[test]
+    fn test_my_total_usage_per_caller() {
+        set_caller(user(1));
+        add_at(0, 3.0, "Fridge");
+        add_at(0, 4.0, "Oven");
+        set_caller(user(2));
+        add_at(0, 10.0, "Heater");
+
+        assert_eq!(my_total_usage(), 10.0);
+        set_caller(user(1));
+        assert_eq!(my_total_usage(), 7.0);
+        set_caller(user(3));
+        assert_eq!(my_total_usage(), 0.0);
+    }
+
+    #[test]
+    fn test_record_at_field_limits_round_trips() {
+        assert_eq!(max_record_size(), EnergyUsage::MAX_SIZE);
+
+        set_caller(user(7));
+        let record = add_energy_usage(EnergyUsagePayload {
+            usage_kwh: f64::MAX,
+            device_type: "d".repeat(MAX_DEVICE_TYPE_LEN),
+            renewable_percent: Some(100.0),
+            ..Default::default()
+        })
+        .unwrap()
+        .record;
+        let record = set_recommendation(record.id, "r".repeat(MAX_RECOMMENDATION_LEN)).unwrap();
+        assert!(record.to_bytes().len() <= max_record_size() as usize);
+
+        let stored = get_energy_usage(record.id).unwrap();
+        assert_eq!(stored.device_type, record.device_type);
+        assert_eq!(stored.recommendation, record.recommendation);
+        assert_eq!(stored.owner, user(7));
+
+        // A record with every field at its limit still fits the storage bound
+        let largest = EnergyUsage {
+            usage_kwh: f64::MAX,
+            device_type: "d".repeat(MAX_DEVICE_TYPE_LEN),
+            recommendation: Some("r".repeat(MAX_RECOMMENDATION_LEN)),
+            is_adjustment: true,
+            renewable_percent: 100.0,
+            owner: user(1),
+            estimated: true,
+            components: vec![1.0; MAX_COMPONENTS],
+            tags: vec!["t".repeat(MAX_TAG_LEN); MAX_TAGS],
+            energy_source: "s".repeat(MAX_ENERGY_SOURCE_LEN),
+            cost_override: Some(1.0),
+            attachment_url: Some("u".repeat(MAX_ATTACHMENT_URL_LEN)),
+            ..Default::default()
+        };
+        assert!(largest.to_bytes().len() <= MAX_RECORD_SIZE as usize);
+    }
+
+    #[test]
+    fn test_get_by_timestamp() {
+        let first = add_at(1_000, 1.0, "Lamp");
+        add_at(2_000, 2.0, "Lamp");
+        let second = add_at(1_000, 3.0, "Oven");
+
+        let ids: Vec<u64> = get_by_timestamp(1_000).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![first.id, second.id]);
+        assert!(get_by_timestamp(1_500).is_empty());
+    }
+
+    #[test]
+    fn test_goal_progress() {
+        set_caller(user(1));
+        set_savings_goal(20.0).unwrap();
+        add_at(0, 90.0, "House");
+
+        let under = goal_progress(100.0);
+        assert_eq!(under.reduction_kwh, 10.0);
+        assert_eq!(under.progress_percent, 50.0);
+        assert_eq!(goal_progress(110.0).progress_percent, 100.0);
+        assert_eq!(goal_progress(120.0).progress_percent, 150.0);
+
+        let increased = goal_progress(80.0);
+        assert_eq!(increased.reduction_kwh, -10.0);
+        assert_eq!(increased.progress_percent, 0.0);
+        assert!(matches!(set_savings_goal(-1.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_usage_deltas() {
+        let third = add_at(30, 3.0, "Lamp");
+        let first = add_at(10, 2.0, "Lamp");
+        let second = add_at(20, 5.0, "Lamp");
+        assert_eq!(
+            usage_deltas(),
+            vec![(first.id, 0.0), (second.id, 3.0), (third.id, -2.0)]
+        );
+    }
+
+    #[test]
+    fn test_device_cov() {
+        // The heater swings widely in absolute terms but little relative to its mean
+        for usage_kwh in [90.0, 100.0, 110.0] {
+            add_at(0, usage_kwh, "Heater");
+        }
+        for usage_kwh in [0.5, 2.5, 0.5] {
+            add_at(0, usage_kwh, "Router");
+        }
+        add_at(0, 4.0, "Kettle");
+
+        let covs = device_cov();
+        assert_eq!(covs.len(), 2);
+        assert_eq!(covs[0].0, "Router");
+        assert_eq!(covs[1].0, "Heater");
+        assert!(covs[0].1 > covs[1].1);
+    }
+
+    #[test]
+    fn test_config_setters_require_admin() {
+        assert!(matches!(set_alert_threshold(5.0), Err(Error::Unauthorized { .. })));
+        assert_eq!(get_admin(), None);
+
+        set_caller(user(1));
+        set_alert_threshold(5.0).unwrap();
+        assert_eq!(get_admin(), Some(user(1)));
+
+        set_caller(user(2));
+        assert!(matches!(set_alert_threshold(9.0), Err(Error::Unauthorized { .. })));
+        assert!(matches!(set_grid_intensity(100.0), Err(Error::Unauthorized { .. })));
+        assert!(matches!(set_savings_goal(1.0), Err(Error::Unauthorized { .. })));
+        assert!(matches!(
+            add_allowed_device_type("Fridge".to_string()),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert_eq!(get_alert_threshold(), 5.0);
+
+        set_caller(user(1));
+        set_alert_threshold(9.0).unwrap();
+        assert_eq!(get_alert_threshold(), 9.0);
+    }
+
+    #[test]
+    fn test_init_config_seeds_getters() {
+        apply_init_config(InitConfig {
+            tariff_per_kwh: Some(0.25),
+            alert_threshold_kwh: Some(12.0),
+            currency: Some("KES".to_string()),
+            admin: Some(user(9)),
+        })
+        .unwrap();
+        assert_eq!(get_tariff(), 0.25);
+        assert_eq!(get_alert_threshold(), 12.0);
+        assert_eq!(get_currency(), "KES");
+        assert_eq!(get_admin(), Some(user(9)));
+
+        // Absent fields keep their values, as on an upgrade without arguments
+        apply_init_config(InitConfig::default()).unwrap();
+        assert_eq!(get_tariff(), 0.25);
+        assert!(matches!(
+            apply_init_config(InitConfig {
+                tariff_per_kwh: Some(-1.0),
+                ..Default::default()
+            }),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_init_without_argument() {
+        // A plain install sends no argument, which only decodes as an option
+        let config = Decode!(&Encode!().unwrap(), Option<InitConfig>).unwrap();
+        assert!(config.is_none());
+        init(config);
+        assert_eq!(get_tariff(), 0.0);
+        assert_eq!(get_admin(), None);
+    }
+
+    // A record as the original canister stored it, before any fields were added
+    #[derive(candid::CandidType, Deserialize)]
+    struct BaselineEnergyUsage {
+        id: u64,
+        usage_kwh: f64,
+        timestamp: u64,
+        device_type: String,
+        recommendation: Option<String>,
+    }
+
+    impl Storable for BaselineEnergyUsage {
+        fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+            Cow::Owned(Encode!(self).unwrap())
+        }
+
+        fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+            Decode!(bytes.as_ref(), Self).unwrap()
+        }
+    }
+
+    impl BoundedStorable for BaselineEnergyUsage {
+        const MAX_SIZE: u32 = 1024;
+        const IS_FIXED_SIZE: bool = false;
+    }
+
+    // Fill stable memory the way the original canister would have left it
+    // with the given (usage_kwh, device_type) readings, before this version
+    // touches it. Must run before anything else in the test reads storage.
+    fn install_baseline_records(readings: &[(f64, &str)]) {
+        let memory = |id| MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+        let mut records: StableBTreeMap<u64, BaselineEnergyUsage, Memory> =
+            StableBTreeMap::init(memory(1));
+        for (id, (usage_kwh, device_type)) in readings.iter().enumerate() {
+            let record = BaselineEnergyUsage {
+                id: id as u64,
+                usage_kwh: *usage_kwh,
+                timestamp: 1_000 + id as u64,
+                device_type: device_type.to_string(),
+                recommendation: Some("Consider turning off.".to_string()),
+            };
+            records.insert(record.id, record);
+        }
+        IdCell::init(memory(0), readings.len() as u64).expect("Cannot create a counter");
+    }
+
+    #[test]
+    fn test_upgrade_keeps_baseline_records() {
+        install_baseline_records(&[(4.0, "Heater"), (2.5, "Lamp")]);
+        post_upgrade(None);
+
+        let usage = get_energy_usage(1).unwrap();
+        assert_eq!((usage.usage_kwh, usage.device_type.as_str()), (2.5, "Lamp"));
+        assert_eq!(usage.recommendation.as_deref(), Some("Consider turning off."));
+        assert!(!usage.is_adjustment && !usage.estimated);
+        assert_eq!(usage.owner, Principal::anonymous());
+        assert_eq!(all_records().len(), 2);
+
+        // New readings take fresh ids and live alongside the old ones
+        let added = add_at(2_000, 1.0, "Fridge");
+        assert_eq!(added.id, 2);
+        assert_eq!(get_energy_usage(0).unwrap().usage_kwh, 4.0);
+    }
+
+    #[test]
+    fn test_rewrite_baseline_record_with_long_device_type() {
+        // Fits the old schema, but not the current one at full length
+        let long_device_type = "d".repeat(800);
+        install_baseline_records(&[(4.0, &long_device_type)]);
+        post_upgrade(None);
+        set_caller(user(1));
+
+        let usage = get_energy_usage(0).unwrap();
+        assert_eq!(usage.device_type, "d".repeat(MAX_DEVICE_TYPE_LEN));
+        assert!(usage.to_bytes().len() <= MAX_RECORD_SIZE as usize);
+        set_recommendation(0, "r".repeat(MAX_RECOMMENDATION_LEN)).unwrap();
+        assign_owner(0, user(2)).unwrap();
+        assert_eq!(compact_ids().unwrap(), 0);
+        assert_eq!(get_energy_usage(0).unwrap().owner, user(2));
+    }
+
+    // Nanosecond timestamp for midnight UTC on a civil date
+    fn date_ns(year: u64, month: u64, day: u64) -> u64 {
+        days_from_civil(year, month, day) * NANOS_PER_DAY
+    }
+
+    #[test]
+    fn test_civil_date_conversions() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(year_month(date_ns(2024, 12, 31) + NANOS_PER_DAY - 1), (2024, 12));
+        assert_eq!(
+            month_bounds((2023, 12)),
+            (date_ns(2023, 12, 1), date_ns(2024, 1, 1))
+        );
+    }
+
+    #[test]
+    fn test_high_tier_this_month() {
+        add_at(date_ns(2024, 4, 30), 25.0, "Heater");
+        let big = add_at(date_ns(2024, 5, 3), 30.0, "Heater");
+        add_at(date_ns(2024, 5, 4), 7.0, "Oven");
+        let bigger = add_at(date_ns(2024, 5, 9), 40.0, "Dryer");
+        add_at(date_ns(2024, 6, 1), 50.0, "Heater");
+
+        set_time(date_ns(2024, 5, 20));
+        let ids: Vec<u64> = high_tier_this_month().iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![bigger.id, big.id]);
+    }
+
+    #[test]
+    fn test_delete_many() {
+        set_caller(user(1));
+        for _ in 0..3 {
+            add_at(0, 1.0, "Lamp");
+        }
+        assert_eq!(delete_many(vec![2, 7, 0, 2]), vec![2, 0]);
+        assert_eq!(all_records().iter().map(|r| r.id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_delete_many_respects_ownership() {
+        set_caller(user(9));
+        set_tariff(0.2).unwrap();
+        set_caller(user(1));
+        let theirs = add_at(0, 1.0, "Lamp");
+        set_caller(user(2));
+        let mine = add_at(1, 2.0, "Heater");
+        assert_eq!(delete_many(vec![theirs.id, mine.id]), vec![mine.id]);
+        assert!(get_energy_usage(theirs.id).is_ok());
+
+        set_caller(Principal::anonymous());
+        assert!(delete_many(vec![theirs.id]).is_empty());
+        set_caller(user(9));
+        assert_eq!(delete_many(vec![theirs.id]), vec![theirs.id]);
+        assert!(all_records().is_empty());
+    }
+
+    // Insert a reading with the given renewable share
+    fn add_renewable(usage_kwh: f64, renewable_percent: f64) -> EnergyUsage {
+        add_energy_usage(EnergyUsagePayload {
+            usage_kwh,
+            device_type: "House".to_string(),
+            renewable_percent: Some(renewable_percent),
+            ..Default::default()
+        })
+        .unwrap()
+        .record
+    }
+
+    #[test]
+    fn test_overall_renewable_percent_is_weighted() {
+        assert_eq!(overall_renewable_percent(), 0.0);
+        add_renewable(9.0, 0.0);
+        add_renewable(1.0, 100.0);
+        // A naive average of the percents would give 50%
+        assert_eq!(overall_renewable_percent(), 10.0);
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let a = add_at(500, 2.0, "Fridge");
+        let b = add_at(500, 3.0, "Fridge");
+        add_at(500, 2.0, "Oven");
+        add_at(600, 2.0, "Fridge");
+        assert_eq!(find_duplicates(), vec![vec![a.id, b.id]]);
+    }
+
+    #[test]
+    fn test_decimal_places_round_reported_cost() {
+        set_caller(user(1));
+        add_at(0, 10.0, "Heater");
+        add_at(0, 2.345, "Lamp");
+        set_tariff(0.17).unwrap();
+
+        // 12.345 kWh at 0.17 is 2.09865 before rounding
+        assert_eq!(get_decimal_places(), DEFAULT_DECIMAL_PLACES);
+        assert_eq!(estimate_cost(None).unwrap(), 2.1);
+        set_decimal_places(0).unwrap();
+        assert_eq!(estimate_cost(None).unwrap(), 2.0);
+        assert_eq!(estimate_cost(Some(1.0)).unwrap(), 12.0);
+        set_decimal_places(3).unwrap();
+        assert_eq!(estimate_cost(None).unwrap(), 2.099);
+
+        assert!(matches!(set_decimal_places(7), Err(Error::InvalidInput { .. })));
+        assert_eq!(get_energy_usage(1).unwrap().usage_kwh, 2.345);
+    }
+
+    #[test]
+    fn test_usage_last_days() {
+        let today = 30 * NANOS_PER_DAY;
+        add_at(today - 10 * NANOS_PER_DAY, 50.0, "Heater");
+        add_at(today - 6 * NANOS_PER_DAY, 3.0, "Heater");
+        add_at(today - NANOS_PER_DAY / 2, 2.0, "Lamp");
+
+        set_time(today);
+        assert_eq!(usage_last_days(7), 5.0);
+        assert_eq!(usage_last_days(30), 55.0);
+        assert_eq!(usage_last_days(0), 0.0);
+    }
+
+    #[test]
+    fn test_text_report() {
+        assert_eq!(text_report(), "Energy usage report\nNo energy usage records.\n");
+
+        add_at(0, 4.0, "Heater");
+        add_at(0, 9.5, "Dryer");
+        add_at(0, 6.0, "Heater");
+        assert_eq!(
+            text_report(),
+            "Energy usage report\n\
+             Total usage: 19.50 kWh\n\
+             Records: 3\n\
+             Top device: Heater (10.00 kWh)\n\
+             Average per reading: 6.50 kWh\n\
+             Highest reading: 9.50 kWh (Dryer, record 1)\n"
+        );
+    }
+
+    #[test]
+    fn test_worst_device() {
+        assert!(matches!(worst_device(), Err(Error::NotFound { .. })));
+
+        for usage_kwh in [1.0, 1.0, 1.0, 1.0] {
+            add_at(0, usage_kwh, "Lamp");
+        }
+        add_at(0, 3.0, "Oven");
+        assert_eq!(worst_device().unwrap(), "Oven");
+
+        add_at(0, 3.0, "Grill");
+        assert_eq!(worst_device().unwrap(), "Grill");
+    }
+
+    #[test]
+    fn test_estimated_readings_excluded_from_metered_total() {
+        add_at(0, 5.0, "Meter");
+        let estimate = add_energy_usage(EnergyUsagePayload {
+            usage_kwh: 3.0,
+            device_type: "Meter".to_string(),
+            estimated: Some(true),
+            ..Default::default()
+        })
+        .unwrap()
+        .record;
+        assert!(estimate.estimated);
+        assert!(!get_energy_usage(0).unwrap().estimated);
+        assert_eq!(total_usage_kwh(), 8.0);
+        assert_eq!(total_usage_metered_only(), 5.0);
+        assert_eq!(total_usage(true), 8.0);
+        assert_eq!(total_usage(false), 5.0);
+    }
+
+    #[test]
+    fn test_forecast_next_month() {
+        set_time(date_ns(2024, 5, 10));
+        add_at(date_ns(2024, 5, 2), 99.0, "House");
+        assert!(matches!(forecast_next_month(), Err(Error::InvalidInput { .. })));
+
+        add_at(date_ns(2024, 1, 15), 500.0, "House");
+        add_at(date_ns(2024, 2, 3), 100.0, "House");
+        add_at(date_ns(2024, 3, 31), 200.0, "House");
+        add_at(date_ns(2024, 4, 1), 250.0, "House");
+        add_at(date_ns(2024, 4, 20), 50.0, "House");
+
+        set_time(date_ns(2024, 5, 10));
+        assert_eq!(forecast_next_month().unwrap(), 200.0);
+
+        // With only two months of history both are used
+        set_time(date_ns(2024, 3, 5));
+        assert_eq!(forecast_next_month().unwrap(), 300.0);
+    }
+
+    #[test]
+    fn test_paused_inserts_are_rejected() {
+        set_caller(user(1));
+        let record = add_at(0, 2.0, "Lamp");
+        set_accepting_inserts(false).unwrap();
+        assert!(!is_accepting_inserts());
+
+        let payload = EnergyUsagePayload {
+            usage_kwh: 2.0,
+            device_type: "Lamp".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(add_energy_usage(payload), Err(Error::Paused { .. })));
+        assert!(matches!(
+            add_adjustment("Solar".to_string(), -1.0),
+            Err(Error::Paused { .. })
+        ));
+        assert!(matches!(duplicate_record(record.id), Err(Error::Paused { .. })));
+        assert!(get_energy_usage(record.id).is_ok());
+
+        set_accepting_inserts(true).unwrap();
+        add_at(0, 2.0, "Lamp");
+        assert_eq!(all_records().len(), 2);
+    }
+
+    #[test]
+    fn test_usage_by_daypart() {
+        add_at(7 * NANOS_PER_HOUR, 2.0, "Kettle");
+        add_at(NANOS_PER_DAY + 11 * NANOS_PER_HOUR, 1.5, "Toaster");
+        add_at(20 * NANOS_PER_HOUR, 4.0, "Oven");
+        assert_eq!(
+            usage_by_daypart(),
+            vec![
+                ("Night".to_string(), 0.0),
+                ("Morning".to_string(), 3.5),
+                ("Afternoon".to_string(), 0.0),
+                ("Evening".to_string(), 4.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_estimate_bill() {
+        add_at(5, 100.0, "House");
+        add_at(10, 20.0, "House");
+        add_at(20, 30.0, "House");
+        add_at(21, 400.0, "House");
+
+        assert_eq!(estimate_bill(10, 20, 0.5, 12.0).unwrap(), 50.0 * 0.5 + 12.0);
+        assert!(matches!(estimate_bill(20, 10, 0.5, 0.0), Err(Error::InvalidInput { .. })));
+        assert!(matches!(estimate_bill(0, 10, -0.5, 0.0), Err(Error::InvalidInput { .. })));
+        assert!(matches!(estimate_bill(0, 10, 0.5, -1.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_estimate_tiered_cost() {
+        add_at(0, 80.0, "House");
+        add_at(0, 70.0, "House");
+
+        // 100 kWh at 0.10 plus the remaining 50 kWh at 0.20
+        assert_eq!(estimate_tiered_cost(vec![(100.0, 0.1), (0.0, 0.2)]).unwrap(), 20.0);
+        assert_eq!(estimate_tiered_cost(vec![(100.0, 0.1), (f64::MAX, 0.2)]).unwrap(), 20.0);
+        assert_eq!(estimate_tiered_cost(vec![(200.0, 0.1), (0.0, 0.2)]).unwrap(), 15.0);
+
+        for invalid in [
+            vec![],
+            vec![(100.0, 0.1), (50.0, 0.2), (0.0, 0.3)],
+            vec![(100.0, -0.1), (0.0, 0.2)],
+            vec![(0.0, 0.1), (0.0, 0.2)],
+        ] {
+            assert!(matches!(estimate_tiered_cost(invalid), Err(Error::InvalidInput { .. })));
+        }
+    }
+
+    #[test]
+    fn test_daily_series_fills_empty_days() {
+        add_at(10 * NANOS_PER_DAY + 5, 2.0, "Fridge");
+        add_at(10 * NANOS_PER_DAY + 9, 1.0, "Fridge");
+        add_at(12 * NANOS_PER_DAY, 4.0, "Fridge");
+        add_at(13 * NANOS_PER_DAY, 8.0, "Fridge");
+
+        assert_eq!(
+            daily_series(10 * NANOS_PER_DAY, 12 * NANOS_PER_DAY + 1).unwrap(),
+            vec![(10, 3.0), (11, 0.0), (12, 4.0)]
+        );
+        assert!(matches!(daily_series(5, 1), Err(Error::InvalidInput { .. })));
+        assert!(matches!(
+            daily_series(0, MAX_SERIES_DAYS * NANOS_PER_DAY),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_set_device_baselines() {
+        set_caller(user(1));
+        assert_eq!(
+            set_device_baselines(vec![("Fridge".to_string(), 1.5), ("Oven".to_string(), 4.0)])
+                .unwrap(),
+            2
+        );
+        assert!(matches!(
+            set_device_baselines(vec![("Heater".to_string(), 3.0), ("Lamp".to_string(), 0.0)]),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert_eq!(
+            get_device_baselines(),
+            vec![("Fridge".to_string(), 1.5), ("Oven".to_string(), 4.0)]
+        );
+    }
+
+    #[test]
+    fn test_recommendation_distribution() {
+        for usage_kwh in [1.0, 2.0, 5.0, 7.0, 12.0, 30.0, 11.0] {
+            add_at(0, usage_kwh, "House");
+        }
+        assert_eq!(recommendation_distribution(), (3, 1, 3));
+    }
+
+    #[test]
+    fn test_device_month_jumps() {
+        add_at(date_ns(2024, 2, 10), 10.0, "Freezer");
+        add_at(date_ns(2024, 3, 2), 18.0, "Freezer");
+        add_at(date_ns(2024, 3, 9), 17.0, "Freezer");
+        add_at(date_ns(2024, 2, 10), 5.0, "Lamp");
+        add_at(date_ns(2024, 3, 10), 5.5, "Lamp");
+        add_at(date_ns(2024, 3, 10), 50.0, "Heater");
+
+        set_time(date_ns(2024, 3, 20));
+        assert_eq!(
+            device_month_jumps(1.5),
+            vec![("Freezer".to_string(), 10.0, 35.0)]
+        );
+    }
+
+    #[test]
+    fn test_time_span() {
+        assert!(matches!(time_span(), Err(Error::NotFound { .. })));
+        add_at(500, 1.0, "Lamp");
+        add_at(100, 1.0, "Lamp");
+        add_at(900, 1.0, "Lamp");
+        assert_eq!(time_span().unwrap(), (100, 900));
+    }
+
+    #[test]
+    fn test_swap_device_types() {
+        let a = add_at(0, 3.0, "Kettle");
+        let b = add_at(0, 9.0, "Microwave");
+        swap_device_types(a.id, b.id).unwrap();
+        assert_eq!(get_energy_usage(a.id).unwrap().device_type, "Microwave");
+        assert_eq!(get_energy_usage(b.id).unwrap().device_type, "Kettle");
+        assert_eq!(get_energy_usage(a.id).unwrap().usage_kwh, 3.0);
+
+        assert!(matches!(swap_device_types(a.id, 99), Err(Error::NotFound { .. })));
+        assert_eq!(get_energy_usage(a.id).unwrap().device_type, "Microwave");
+    }
+
+    #[test]
+    fn test_device_usage_entropy() {
+        assert_eq!(device_usage_entropy(), 0.0);
+        add_at(0, 5.0, "Fridge");
+        assert_eq!(device_usage_entropy(), 0.0);
+        add_at(0, 2.0, "Oven");
+        add_at(0, 3.0, "Oven");
+        assert!((device_usage_entropy() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_savings_opportunities() {
+        add_at(0, 40.0, "Heater");
+        add_at(0, 60.0, "Heater");
+        add_at(0, 20.0, "Lamp");
+        assert_eq!(
+            savings_opportunities(10.0, 0.5).unwrap(),
+            vec![("Heater".to_string(), 5.0), ("Lamp".to_string(), 1.0)]
+        );
+        assert!(matches!(savings_opportunities(120.0, 0.5), Err(Error::InvalidInput { .. })));
+        assert!(matches!(savings_opportunities(10.0, -0.5), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_snapshot_daily_totals() {
+        add_at(NANOS_PER_DAY, 2.0, "Fridge");
+        add_at(NANOS_PER_DAY + 5, 3.0, "Fridge");
+        let late = add_at(4 * NANOS_PER_DAY, 1.0, "Fridge");
+        assert!(get_daily_snapshot().is_empty());
+
+        assert_eq!(snapshot_daily_totals(), 2);
+        assert_eq!(get_daily_snapshot(), vec![(1, 5.0), (4, 1.0)]);
+
+        // The snapshot is point-in-time and keeps days after their records go
+        delete_energy_usage(late.id).unwrap();
+        add_at(NANOS_PER_DAY, 1.0, "Fridge");
+        assert_eq!(get_daily_snapshot(), vec![(1, 5.0), (4, 1.0)]);
+        assert_eq!(snapshot_daily_totals(), 1);
+        assert_eq!(get_daily_snapshot(), vec![(1, 6.0), (4, 1.0)]);
+    }
+
+    #[test]
+    fn test_iqr_outliers() {
+        for (n, kwh) in [2.0, 2.5, 3.0, 3.5].into_iter().enumerate() {
+            add_at(n as u64, kwh, "Fridge");
+        }
+        assert!(iqr_outliers().is_empty());
+
+        let spike = add_at(10, 40.0, "Heater");
+        add_at(11, 2.8, "Fridge");
+        let outliers = iqr_outliers();
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].id, spike.id);
+    }
+
+    #[test]
+    fn test_iqr_outliers_needs_four_records() {
+        add_at(1, 1.0, "Fridge");
+        add_at(2, 1.0, "Fridge");
+        add_at(3, 90.0, "Heater");
+        assert!(iqr_outliers().is_empty());
+    }
+
+    #[test]
+    fn test_input_precision() {
+        set_caller(user(1));
+        assert_eq!(add_at(1, 1.23456, "Fridge").usage_kwh, 1.23456);
+
+        set_input_precision(2).unwrap();
+        assert_eq!(get_input_precision(), 2);
+        assert_eq!(add_at(2, 1.23456, "Fridge").usage_kwh, 1.23);
+        let tiny = || EnergyUsagePayload {
+            usage_kwh: 0.001,
+            device_type: "Fridge".to_string(),
+            ..Default::default()
+        };
+        // The dry run rejects what the insert would reject
+        assert!(matches!(validate_payload(tiny()), Err(Error::InvalidInput { .. })));
+        assert!(matches!(add_energy_usage(tiny()), Err(Error::InvalidInput { .. })));
+        assert!(matches!(set_input_precision(7), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_inactive_devices() {
+        add_at(NANOS_PER_DAY, 1.0, "Heater");
+        add_at(2 * NANOS_PER_DAY, 1.0, "Fridge");
+        add_at(9 * NANOS_PER_DAY, 1.0, "Fridge");
+        set_time(10 * NANOS_PER_DAY);
+
+        assert_eq!(inactive_devices(7), vec!["Heater".to_string()]);
+        assert_eq!(inactive_devices(0), vec!["Fridge".to_string(), "Heater".to_string()]);
+        assert!(inactive_devices(30).is_empty());
+    }
+
+    #[test]
+    fn test_cost_extremes() {
+        assert!(matches!(cost_extremes(), Err(Error::NotFound { .. })));
+        set_caller(user(1));
+        set_tariff(0.2).unwrap();
+        add_at(1, 4.0, "Fridge");
+        let cheapest = add_at(2, 0.5, "Lamp");
+        let priciest = add_at(3, 12.0, "Heater");
+
+        let (low, high) = cost_extremes().unwrap();
+        assert_eq!(low.id, cheapest.id);
+        assert_eq!(high.id, priciest.id);
+    }
+
+    #[test]
+    fn test_apply_percentile_recommendations() {
+        set_caller(user(1));
+        let low = add_at(1, 1.0, "Lamp");
+        let middle = add_at(2, 2.0, "Lamp");
+        add_at(3, 3.0, "Fridge");
+        let top = add_at(4, 4.0, "Heater");
+        add_adjustment("Heater".to_string(), -1.0).unwrap();
+
+        // With 1-4 kWh everything is Low under the default thresholds
+        assert_eq!(apply_percentile_recommendations(25.0, 75.0).unwrap(), 3);
+        let high = recommendation_for_level(RecommendationLevel::High);
+        let moderate = recommendation_for_level(RecommendationLevel::Moderate);
+        assert_eq!(get_energy_usage(top.id).unwrap().recommendation, Some(high));
+        assert_eq!(get_energy_usage(middle.id).unwrap().recommendation, Some(moderate));
+        assert_eq!(get_energy_usage(low.id).unwrap().recommendation, low.recommendation);
+        assert_eq!(apply_percentile_recommendations(25.0, 75.0).unwrap(), 0);
+
+        assert!(apply_percentile_recommendations(75.0, 25.0).is_err());
+        assert!(apply_percentile_recommendations(50.0, 101.0).is_err());
+
+        set_caller(user(2));
+        assert!(matches!(
+            apply_percentile_recommendations(25.0, 75.0),
+            Err(Error::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn test_estimate_runtime_hours() {
+        set_caller(user(1));
+        let heater = add_at(1, 2.0, "Heater");
+        let lamp = add_at(2, 1.0, "Lamp");
+        let fridge = add_at(3, 1.0, "Fridge");
+        set_device_rated_watts("Heater".to_string(), 1_000.0).unwrap();
+        set_device_rated_watts("Lamp".to_string(), 0.0).unwrap();
+
+        assert_eq!(estimate_runtime_hours(heater.id).unwrap(), 2.0);
+        assert!(matches!(estimate_runtime_hours(lamp.id), Err(Error::InvalidInput { .. })));
+        assert!(matches!(estimate_runtime_hours(fridge.id), Err(Error::NotFound { .. })));
+        assert!(matches!(estimate_runtime_hours(999), Err(Error::NotFound { .. })));
+        assert!(set_device_rated_watts("Lamp".to_string(), -5.0).is_err());
+    }
+
+    #[test]
+    fn test_monthly_renewable_trend() {
+        set_time(date_ns(2024, 1, 10));
+        add_renewable(3.0, 100.0);
+        add_renewable(1.0, 0.0);
+        set_time(date_ns(2024, 2, 5));
+        add_renewable(1.0, 50.0);
+        add_renewable(4.0, 25.0);
+
+        assert_eq!(
+            monthly_renewable_trend(),
+            vec![(2024, 1, 75.0), (2024, 2, 30.0)]
+        );
+    }
+
+    #[test]
+    fn test_near_capacity_warning() {
+        set_caller(user(1));
+        set_warn_at_records(2).unwrap();
+        let payload = || EnergyUsagePayload {
+            usage_kwh: 1.0,
+            device_type: "Fridge".to_string(),
+            ..Default::default()
+        };
+        assert!(!add_energy_usage(payload()).unwrap().near_capacity);
+        assert!(add_energy_usage(payload()).unwrap().near_capacity);
+        assert!(add_energy_usage(payload()).unwrap().near_capacity);
+        assert_eq!(all_records().len(), 3);
+        assert!(set_warn_at_records(MAX_RECORDS + 1).is_err());
+    }
+
+    #[test]
+    fn test_query_records() {
+        let fridge_early = add_at(10, 2.0, "Fridge");
+        let heater = add_at(20, 8.0, "Heater");
+        let fridge_late = add_at(30, 6.0, "Fridge");
+        let ids = |page: EnergyUsagePage| -> Vec<u64> {
+            assert!(!page.truncated);
+            page.records.into_iter().map(|usage| usage.id).collect()
+        };
+
+        assert_eq!(
+            ids(query_records(None, None, None)),
+            vec![fridge_early.id, heater.id, fridge_late.id]
+        );
+        assert_eq!(
+            ids(query_records(Some("Fridge".to_string()), None, None)),
+            vec![fridge_early.id, fridge_late.id]
+        );
+        assert_eq!(ids(query_records(None, Some(20), None)), vec![heater.id, fridge_late.id]);
+        assert_eq!(ids(query_records(None, None, Some(6.0))), vec![heater.id, fridge_late.id]);
+        assert_eq!(
+            ids(query_records(Some("Fridge".to_string()), Some(15), Some(5.0))),
+            vec![fridge_late.id]
+        );
+        assert!(query_records(Some("Lamp".to_string()), None, None).records.is_empty());
+
+        fill_past_response_budget();
+        let page = query_records(None, None, None);
+        assert!(page.truncated);
+        assert!(page.records.len() < 5_003);
+        assert_eq!(ids(query_records(Some("Fridge".to_string()), None, None)).len(), 2);
+    }
+
+    #[test]
+    fn test_baseline_variance() {
+        set_caller(user(1));
+        set_device_baselines(vec![
+            ("Fridge".to_string(), 2.0),
+            ("Heater".to_string(), 10.0),
+            ("Lamp".to_string(), 1.0),
+        ])
+        .unwrap();
+        add_at(1, 3.0, "Fridge");
+        add_at(2, 5.0, "Fridge");
+        add_at(3, 5.0, "Heater");
+        add_at(4, 9.0, "Dryer");
+
+        assert_eq!(
+            baseline_variance(),
+            vec![("Fridge".to_string(), 2.0, 4.0), ("Heater".to_string(), 10.0, 5.0)]
+        );
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(3).unwrap(), vec![0.0, 0.0, 0.0]);
+        add_at(100, 1.0, "Fridge");
+        add_at(105, 2.0, "Fridge");
+        add_at(125, 3.0, "Heater");
+        add_at(139, 4.0, "Fridge");
+
+        assert_eq!(sparkline(4).unwrap(), vec![3.0, 0.0, 3.0, 4.0]);
+        assert_eq!(sparkline(1).unwrap(), vec![10.0]);
+        assert!(sparkline(0).is_err());
+        assert!(sparkline(MAX_SPARKLINE_BUCKETS + 1).is_err());
+    }
+
+    #[test]
+    fn test_usage_gini() {
+        assert_eq!(usage_gini(), 0.0);
+        for ts in 1..=4 {
+            add_at(ts, 2.0, "Fridge");
+        }
+        assert!(usage_gini().abs() < 1e-9);
+
+        add_at(5, 1_000.0, "Heater");
+        assert!(usage_gini() > 0.75);
+    }
+
+    #[test]
+    fn test_would_exceed_budget() {
+        set_caller(user(1));
+        set_time(date_ns(2024, 1, 31));
+        add_renewable(50.0, 0.0);
+        set_time(date_ns(2024, 2, 10));
+        add_renewable(80.0, 0.0);
+        assert!(!would_exceed_budget(1_000.0));
+
+        set_monthly_budget(100.0).unwrap();
+        assert!(!would_exceed_budget(20.0));
+        assert!(would_exceed_budget(20.5));
+        assert_eq!(all_records().len(), 2);
+        assert!(set_monthly_budget(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_devices_by_recency() {
+        add_at(10, 1.0, "fridge");
+        add_at(20, 1.0, "Heater");
+        add_at(30, 1.0, "Lamp");
+        add_at(40, 1.0, "Fridge");
+
+        assert_eq!(
+            devices_by_recency(),
+            vec![
+                ("Fridge".to_string(), 40),
+                ("Lamp".to_string(), 30),
+                ("Heater".to_string(), 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_composite_usage() {
+        let composite = add_composite_usage("Main".to_string(), vec![4.0, 3.5, 0.0]).unwrap();
+        assert_eq!(composite.usage_kwh, 7.5);
+        assert_eq!(composite.components, vec![4.0, 3.5, 0.0]);
+        assert_eq!(composite.recommendation, Some(generate_recommendation(7.5)));
+        assert_eq!(get_energy_usage(composite.id).unwrap().components, composite.components);
+    }
+
+    #[test]
+    fn test_add_composite_usage_rejects_bad_components() {
+        let composite = |components| add_composite_usage("Main".to_string(), components);
+        assert!(matches!(composite(vec![]), Err(Error::InvalidInput { .. })));
+        assert!(matches!(composite(vec![2.0, -1.0]), Err(Error::InvalidInput { .. })));
+        assert!(matches!(composite(vec![0.0, 0.0]), Err(Error::InvalidInput { .. })));
+        assert!(matches!(
+            composite(vec![1.0; MAX_COMPONENTS + 1]),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(all_records().is_empty());
+    }
+
+    #[test]
+    fn test_current_month_device_shares() {
+        assert!(current_month_device_shares().is_empty());
+        add_at(date_ns(2024, 2, 20), 9.0, "Heater");
+        add_at(date_ns(2024, 3, 2), 1.0, "Fridge");
+        add_at(date_ns(2024, 3, 5), 3.0, "Heater");
+        add_at(date_ns(2024, 3, 9), 2.0, "Fridge");
+
+        let shares = current_month_device_shares();
+        assert_eq!(shares, vec![("Fridge".to_string(), 50.0), ("Heater".to_string(), 50.0)]);
+        add_at(date_ns(2024, 3, 10), 2.0, "Lamp");
+        let shares = current_month_device_shares();
+        assert_eq!(shares[2], ("Lamp".to_string(), 25.0));
+        let sum: f64 = shares.iter().map(|(_, share)| share).sum();
+        assert!((sum - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tag_matching() {
+        let fridge = add_at(1, 1.0, "Fridge");
+        add_at(2, 2.0, "Fridge");
+        let heater = add_at(3, 3.0, "Heater");
+
+        assert_eq!(tag_matching("Fridge".to_string(), "kitchen".to_string()).unwrap(), 2);
+        assert_eq!(tag_matching("Fridge".to_string(), "kitchen".to_string()).unwrap(), 0);
+        assert_eq!(get_energy_usage(fridge.id).unwrap().tags, vec!["kitchen".to_string()]);
+        assert!(get_energy_usage(heater.id).unwrap().tags.is_empty());
+
+        assert!(tag_matching(String::new(), "kitchen".to_string()).is_err());
+        assert!(tag_matching("Fridge".to_string(), String::new()).is_err());
+        assert!(tag_matching("Fridge".to_string(), "t".repeat(MAX_TAG_LEN + 1)).is_err());
+        for n in 1..MAX_TAGS {
+            tag_matching("Heater".to_string(), format!("tag{}", n)).unwrap();
+        }
+        assert!(tag_matching("Heater".to_string(), "kitchen".to_string()).is_ok());
+        assert!(tag_matching("Heater".to_string(), "extra".to_string()).is_err());
+
+        // Dropping a tag from the full record makes room again
+        let tags = vec!["kitchen".to_string(), "tag1".to_string(), "kitchen".to_string()];
+        let heater = set_tags(heater.id, tags).unwrap();
+        assert_eq!(heater.tags, vec!["kitchen".to_string(), "tag1".to_string()]);
+        assert_eq!(tag_matching("Heater".to_string(), "extra".to_string()).unwrap(), 1);
+        let too_many: Vec<String> = (0..=MAX_TAGS).map(|n| format!("tag{}", n)).collect();
+        assert!(matches!(set_tags(heater.id, too_many), Err(Error::InvalidInput { .. })));
+        assert!(set_tags(heater.id, vec![String::new()]).is_err());
+        assert!(matches!(set_tags(99, Vec::new()), Err(Error::NotFound { .. })));
+        assert!(set_tags(heater.id, Vec::new()).unwrap().tags.is_empty());
+    }
+
+    #[test]
+    fn test_load_shift_savings() {
+        add_at(18 * NANOS_PER_HOUR, 6.0, "Oven");
+        add_at(NANOS_PER_DAY + 20 * NANOS_PER_HOUR, 4.0, "Heater");
+        add_at(NANOS_PER_DAY + 3 * NANOS_PER_HOUR, 5.0, "Heater");
+
+        // 50% of the 10 kWh peak usage moves from 0.30 to 0.10
+        assert_eq!(load_shift_savings(17, 21, 0.3, 0.1, 50.0).unwrap(), 1.0);
+        // A window wrapping past midnight also catches the 03:00 reading
+        assert_eq!(load_shift_savings(20, 4, 0.3, 0.1, 100.0).unwrap(), 1.8);
+        assert!(load_shift_savings(17, 24, 0.3, 0.1, 50.0).is_err());
+        assert!(load_shift_savings(17, 17, 0.3, 0.1, 50.0).is_err());
+        assert!(load_shift_savings(17, 21, -0.3, 0.1, 50.0).is_err());
+        assert!(load_shift_savings(17, 21, 0.3, 0.1, 150.0).is_err());
+    }
+
+    #[test]
+    fn test_future_dated_records() {
+        add_at(100, 1.0, "Fridge");
+        let later = add_at(300, 1.0, "Fridge");
+        let latest = add_at(500, 1.0, "Fridge");
+        assert!(future_dated_records().is_empty());
+
+        // Simulate the clock stepping back after the readings were stored
+        set_time(200);
+        let ids: Vec<u64> = future_dated_records().iter().map(|usage| usage.id).collect();
+        assert_eq!(ids, vec![latest.id, later.id]);
+    }
+
+    #[test]
+    fn test_normalized_readings() {
+        set_caller(user(1));
+        set_device_baselines(vec![("Heater".to_string(), 5.0)]).unwrap();
+        let over = add_at(1, 10.0, "Heater");
+        add_at(2, 3.0, "Fridge");
+        let under = add_at(3, 2.5, "Heater");
+
+        assert_eq!(normalized_readings(), vec![(over.id, 2.0), (under.id, 0.5)]);
+    }
+
+    #[test]
+    fn test_busiest_day() {
+        assert!(matches!(busiest_day(), Err(Error::NotFound { .. })));
+        add_at(NANOS_PER_DAY, 3.0, "Fridge");
+        add_at(2 * NANOS_PER_DAY, 2.0, "Fridge");
+        add_at(2 * NANOS_PER_DAY + 1, 2.5, "Heater");
+        assert_eq!(busiest_day().unwrap(), (2, 4.5));
+
+        add_at(NANOS_PER_DAY + 1, 1.5, "Heater");
+        assert_eq!(busiest_day().unwrap(), (1, 4.5));
+    }
+
+    #[test]
+    fn test_emission_factors_per_source() {
+        set_caller(user(1));
+        let add_from = |source: &str| {
+            add_energy_usage(EnergyUsagePayload {
+                usage_kwh: 10.0,
+                device_type: "Heater".to_string(),
+                energy_source: Some(source.to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+            .record
+        };
+        add_from("coal");
+        assert_eq!(estimate_co2_kg(Some(500.0)).unwrap(), 5.0);
+
+        set_emission_factor("coal".to_string(), 1_000.0).unwrap();
+        set_emission_factor("solar".to_string(), 40.0).unwrap();
+        assert_eq!(estimate_co2_kg(Some(500.0)).unwrap(), 10.0);
+        let solar = add_from("solar");
+        assert_eq!(solar.energy_source, "solar");
+        assert_eq!(estimate_co2_kg(Some(500.0)).unwrap(), 10.4);
+        assert_eq!(get_emission_factors().len(), 2);
+
+        assert!(set_emission_factor(String::new(), 10.0).is_err());
+        assert!(set_emission_factor("coal".to_string(), -1.0).is_err());
+        assert!(add_energy_usage(EnergyUsagePayload {
+            usage_kwh: 1.0,
+            device_type: "Heater".to_string(),
+            energy_source: Some("s".repeat(MAX_ENERGY_SOURCE_LEN + 1)),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_median_usage_by_device() {
+        for (ts, kwh) in [(1, 9.0), (2, 1.0), (3, 4.0)] {
+            add_at(ts, kwh, "Fridge");
+        }
+        for (ts, kwh) in [(4, 2.0), (5, 30.0), (6, 6.0), (7, 10.0)] {
+            add_at(ts, kwh, "Heater");
+        }
+        assert_eq!(
+            median_usage_by_device(),
+            vec![("Heater".to_string(), 8.0), ("Fridge".to_string(), 4.0)]
+        );
+    }
+
+    #[test]
+    fn test_renewable_co2_avoided() {
+        assert_eq!(renewable_co2_avoided(400.0).unwrap(), 0.0);
+        add_renewable(10.0, 50.0);
+        add_renewable(4.0, 25.0);
+        add_renewable(6.0, 0.0);
+
+        // 5 kWh + 1 kWh renewable at 400 g/kWh
+        assert_eq!(renewable_co2_avoided(400.0).unwrap(), 2.4);
+        // Emitted and avoided CO2 together make up all 20 kWh at 400 g/kWh
+        assert_eq!(estimate_co2_kg(Some(400.0)).unwrap(), 5.6);
+        assert!(matches!(renewable_co2_avoided(-1.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_daily_heatmap() {
+        add_at(3 * NANOS_PER_DAY + 5, 2.0, "Fridge");
+        add_at(3 * NANOS_PER_DAY + 9, 1.0, "Fridge");
+        add_at(5 * NANOS_PER_DAY, 4.0, "Heater");
+        add_at(9 * NANOS_PER_DAY, 8.0, "Heater");
+
+        assert_eq!(
+            daily_heatmap(2 * NANOS_PER_DAY + 7, 5).unwrap(),
+            vec![0.0, 3.0, 0.0, 4.0, 0.0]
+        );
+        assert!(daily_heatmap(0, 0).is_err());
+        assert!(daily_heatmap(0, MAX_SERIES_DAYS + 1).is_err());
+    }
+
+    #[test]
+    fn test_compare_tariffs() {
+        add_at(0, 30.0, "Heater");
+        add_at(10 * NANOS_PER_DAY, 20.0, "Heater");
+
+        // 50 kWh over 10 days projects to 1825 kWh a year
+        assert_eq!(
+            compare_tariffs(0.2, 0.15).unwrap(),
+            TariffComparison {
+                total_usage_kwh: 50.0,
+                current_cost: 10.0,
+                proposed_cost: 7.5,
+                annual_difference: -91.25,
+            }
+        );
+        assert!(matches!(compare_tariffs(-0.2, 0.15), Err(Error::InvalidInput { .. })));
+
+        // An overridden record costs its override under either rate
+        let billed = add_at(5 * NANOS_PER_DAY, 10.0, "EV");
+        set_cost_override(billed.id, Some(1.0)).unwrap();
+        assert_eq!(
+            compare_tariffs(0.2, 0.15).unwrap(),
+            TariffComparison {
+                total_usage_kwh: 60.0,
+                current_cost: 11.0,
+                proposed_cost: 8.5,
+                annual_difference: -91.25,
+            }
+        );
+    }
+
+    #[test]
+    fn test_recommendation_message_counts() {
+        add_at(1, 2.0, "Lamp");
+        add_at(2, 3.0, "Lamp");
+        add_at(3, 12.0, "Heater");
+        let custom = add_at(4, 1.0, "Lamp");
+        set_recommendation(custom.id, "Unplug it".to_string()).unwrap();
+        add_adjustment("Heater".to_string(), -1.0).unwrap();
+
+        assert_eq!(
+            recommendation_message_counts(),
+            vec![
+                (recommendation_for_level(RecommendationLevel::Low), 2),
+                (recommendation_for_level(RecommendationLevel::High), 1),
+                ("Unplug it".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_cost_override() {
+        set_caller(user(1));
+        set_tariff(0.5).unwrap();
+        let promo = add_at(1, 40.0, "EV");
+        add_at(2, 10.0, "Heater");
+        assert_eq!(estimate_cost(None).unwrap(), 25.0);
+
+        let promo = set_cost_override(promo.id, Some(2.0)).unwrap();
+        assert_eq!(promo.cost_override, Some(2.0));
+        assert_eq!(record_cost(&promo), 2.0);
+        assert_eq!(estimate_cost(None).unwrap(), 7.0);
+        assert_eq!(estimate_cost(Some(1.0)).unwrap(), 12.0);
+        assert_eq!(estimate_bill(0, 1, 1.0, 3.0).unwrap(), 5.0);
+
+        set_cost_override(promo.id, None).unwrap();
+        assert_eq!(estimate_cost(None).unwrap(), 25.0);
+        assert!(matches!(set_cost_override(promo.id, Some(-1.0)), Err(Error::InvalidInput { .. })));
+        assert!(matches!(set_cost_override(999, Some(1.0)), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_total_cost() {
+        assert_eq!(total_cost(), 0.0);
+        set_caller(user(1));
+        set_tariff(0.2).unwrap();
+        add_at(1, 10.0, "Heater");
+        let promo = add_at(2, 30.0, "EV");
+        set_cost_override(promo.id, Some(1.5)).unwrap();
+        add_at(3, 5.0, "Lamp");
+
+        assert_eq!(total_cost(), 4.5);
+    }
+
+    #[test]
+    fn test_records_by_cost() {
+        set_caller(user(1));
+        set_tariff(0.1).unwrap();
+        let heater = add_at(1, 20.0, "Heater");
+        let lamp = add_at(2, 1.0, "Lamp");
+        let fridge = add_at(3, 5.0, "Fridge");
+        set_cost_override(lamp.id, Some(9.0)).unwrap();
+
+        let page = records_by_cost();
+        assert!(!page.truncated);
+        let ranked: Vec<(u64, f64)> =
+            page.records.into_iter().map(|(usage, cost)| (usage.id, cost)).collect();
+        assert_eq!(ranked, vec![(lamp.id, 9.0), (heater.id, 2.0), (fridge.id, 0.5)]);
+
+        fill_past_response_budget();
+        let page = records_by_cost();
+        assert!(page.truncated);
+        assert_eq!(page.records[0].0.id, lamp.id);
+    }
+
+    #[test]
+    fn test_replacement_savings() {
+        add_at(1, 12.0, "Fridge");
+        add_at(2, 8.0, "Fridge");
+        add_at(3, 50.0, "Heater");
+
+        let saved = replacement_savings("Fridge".to_string(), 0.3).unwrap();
+        assert!((saved - 6.0).abs() < 1e-9);
+        assert_eq!(replacement_savings("Fridge".to_string(), 0.0).unwrap(), 0.0);
+        assert!(matches!(
+            replacement_savings("Lamp".to_string(), 0.3),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            replacement_savings("Fridge".to_string(), 1.5),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bucketed_stats() {
+        add_at(0, 1.0, "Fridge");
+        add_at(3, 3.0, "Fridge");
+        add_at(10, 8.0, "Heater");
+        add_at(19, 2.0, "Heater");
+
+        let stat = |min_kwh, max_kwh, avg_kwh, count| BucketStat {
+            min_kwh,
+            max_kwh,
+            avg_kwh,
+            count,
+        };
+        assert_eq!(
+            bucketed_stats(3).unwrap(),
+            vec![stat(1.0, 3.0, 2.0, 2), stat(8.0, 8.0, 8.0, 1), stat(2.0, 2.0, 2.0, 1)]
+        );
+        assert_eq!(bucketed_stats(4).unwrap()[1], BucketStat::default());
+        assert!(bucketed_stats(0).is_err());
+    }
+
+    #[test]
+    fn test_stale_recommendations() {
+        set_caller(user(1));
+        add_at(1, 2.0, "Lamp");
+        let heater = add_at(2, 8.0, "Heater");
+        add_adjustment("Heater".to_string(), -1.0).unwrap();
+        assert!(stale_recommendations().is_empty());
+
+        set_recommendation_thresholds(3.0, 7.0).unwrap();
+        assert_eq!(get_recommendation_thresholds(), (3.0, 7.0));
+        assert_eq!(stale_recommendations(), vec![heater.id]);
+        assert!(set_recommendation_thresholds(7.0, 3.0).is_err());
+        assert!(set_recommendation_thresholds(-1.0, 3.0).is_err());
+    }
+
+    #[test]
+    fn test_set_attachment() {
+        let usage = add_at(1, 2.0, "Fridge");
+        let url = "https://photos.example.com/meter/1.jpg".to_string();
+        let attached = set_attachment(usage.id, url.clone()).unwrap();
+        assert_eq!(attached.attachment_url, Some(url.clone()));
+        assert_eq!(get_energy_usage(usage.id).unwrap().attachment_url, Some(url));
+
+        set_attachment(usage.id, String::new()).unwrap();
+        assert_eq!(get_energy_usage(usage.id).unwrap().attachment_url, None);
+        assert!(matches!(
+            set_attachment(999, "https://example.com/a.jpg".to_string()),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_set_attachment_rejects_bad_urls() {
+        let usage = add_at(1, 2.0, "Fridge");
+        let too_long = format!("https://{}", "a".repeat(MAX_ATTACHMENT_URL_LEN));
+        for url in ["ftp://example.com/a.jpg", "https://", "http://a b.jpg", too_long.as_str()] {
+            assert!(matches!(
+                set_attachment(usage.id, url.to_string()),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+        assert_eq!(get_energy_usage(usage.id).unwrap().attachment_url, None);
+    }
+
+    #[test]
+    fn test_rolling_30day_series() {
+        add_at(10 * NANOS_PER_DAY, 5.0, "Heater");
+        add_at(30 * NANOS_PER_DAY, 2.0, "Heater");
+        add_at(40 * NANOS_PER_DAY, 1.0, "Heater");
+
+        let series = rolling_30day_series(3).unwrap();
+        assert_eq!(series, vec![(38, 7.0), (39, 7.0), (40, 3.0)]);
+        assert_eq!(rolling_30day_series(50).unwrap().len(), 41);
+        assert!(rolling_30day_series(0).is_err());
+        assert!(rolling_30day_series(MAX_SERIES_DAYS + 1).is_err());
+    }
+
+    #[test]
+    fn test_per_capita_usage() {
+        add_at(1, 30.0, "Heater");
+        add_at(2, 10.0, "Fridge");
+        assert!(matches!(per_capita_usage(), Err(Error::InvalidInput { .. })));
+
+        set_caller(user(1));
+        set_household_size(4).unwrap();
+        assert_eq!(get_household_size(), 4);
+        assert_eq!(per_capita_usage().unwrap(), 10.0);
+
+        set_household_size(0).unwrap();
+        assert!(matches!(per_capita_usage(), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_top_action() {
+        assert_eq!(top_action(0.2).unwrap(), None);
+        add_at(1, 6.0, "Fridge");
+        add_at(2, 4.0, "Fridge");
+        add_at(3, 15.0, "Heater");
+        add_at(4, 7.0, "Heater");
+        add_at(5, 12.0, "Dryer");
+
+        let action = top_action(0.5).unwrap().unwrap();
+        assert_eq!(action.device_type, "Heater");
+        assert_eq!(action.total_cost, 11.0);
+        assert!(action.message.starts_with("Heater is your biggest energy cost at 11 USD."));
+        assert!(matches!(top_action(-1.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_compact_ids() {
+        set_caller(user(1));
+        let first = add_at(10, 1.0, "Fridge");
+        let middle = add_at(20, 2.0, "Fridge");
+        add_at(30, 3.0, "Heater");
+        add_at(40, 4.0, "Heater");
+        delete_energy_usage(middle.id).unwrap();
+        assert_eq!(first.id, 0);
+
+        assert_eq!(compact_ids().unwrap(), 2);
+        let compacted: Vec<(u64, f64)> =
+            records_by_time().iter().map(|usage| (usage.id, usage.usage_kwh)).collect();
+        assert_eq!(compacted, vec![(0, 1.0), (1, 3.0), (2, 4.0)]);
+        assert_eq!(add_at(50, 5.0, "Lamp").id, 3);
+        assert_eq!(compact_ids().unwrap(), 0);
+
+        set_caller(user(2));
+        assert!(matches!(compact_ids(), Err(Error::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn test_base_vs_variable_load() {
+        assert!(matches!(base_vs_variable_load(), Err(Error::NotFound { .. })));
+        add_at(NANOS_PER_DAY, 4.0, "Fridge");
+        add_at(2 * NANOS_PER_DAY, 3.0, "Fridge");
+        add_at(2 * NANOS_PER_DAY + 1, 1.0, "Heater");
+        add_at(3 * NANOS_PER_DAY, 9.0, "Heater");
+
+        assert_eq!(base_vs_variable_load().unwrap(), (12.0, 5.0));
+    }
+
+    #[test]
+    fn test_export_device_csv() {
+        assert_eq!(export_device_csv("Fridge".to_string()), format!("{}\n", CSV_HEADER));
+        let fridge = add_at(1, 2.5, "Fridge");
+        add_at(2, 4.0, "Heater");
+        let quoted = add_at(3, 1.0, "fridge");
+        set_recommendation(quoted.id, "Check the \"eco\" mode, then defrost".to_string()).unwrap();
+
+        let csv = export_device_csv("FRIDGE".to_string());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            format!("{},1,Fridge,2.5,{}", fridge.id, fridge.recommendation.unwrap())
+        );
+        assert_eq!(
+            lines[2],
+            format!("{},3,fridge,1,\"Check the \"\"eco\"\" mode, then defrost\"", quoted.id)
+        );
+    }
+
+    #[test]
+    fn test_demand_charge() {
+        assert!(matches!(demand_charge(5.0), Err(Error::NotFound { .. })));
+        add_at(1, 4.0, "Fridge");
+        add_at(2, 12.0, "Heater");
+        add_at(3, 7.5, "Oven");
+
+        assert_eq!(demand_charge(5.0).unwrap(), 60.0);
+        assert!(matches!(demand_charge(-5.0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_merge_device_types() {
+        set_caller(user(1));
+        set_device_baselines(vec![("AC".to_string(), 3.0)]).unwrap();
+        add_at(1, 2.0, "AC");
+        add_at(2, 4.0, "AC");
+        add_at(3, 5.0, "Air Conditioner");
+        add_at(4, 1.0, "Fridge");
+
+        assert_eq!(merge_device_types("AC".to_string(), "Air Conditioner".to_string()).unwrap(), 2);
+        assert_eq!(totals_by_device().get("Air Conditioner"), Some(&11.0));
+        assert!(!totals_by_device().contains_key("AC"));
+        assert_eq!(get_device_baselines(), vec![("Air Conditioner".to_string(), 3.0)]);
+
+        // An existing baseline on the target is kept
+        set_device_baselines(vec![("Fridge".to_string(), 1.0)]).unwrap();
+        merge_device_types("Air Conditioner".to_string(), "Fridge".to_string()).unwrap();
+        assert_eq!(get_device_baselines(), vec![("Fridge".to_string(), 1.0)]);
+
+        assert!(merge_device_types("Fridge".to_string(), String::new()).is_err());
+        assert!(merge_device_types("Fridge".to_string(), "Fridge".to_string()).is_err());
+
+        set_caller(user(2));
+        assert!(matches!(
+            merge_device_types("Fridge".to_string(), "Freezer".to_string()),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert_eq!(totals_by_device().get("Fridge"), Some(&12.0));
+    }
+
+    #[test]
+    fn test_self_sufficiency_percent() {
+        assert_eq!(self_sufficiency_percent(), 0.0);
+        add_renewable(8.0, 100.0);
+        add_renewable(2.0, 0.0);
+        add_renewable(10.0, 20.0);
+
+        // (8 + 0 + 2) renewable kWh of 20 kWh, not the 40% mean of percents
+        assert_eq!(self_sufficiency_percent(), 50.0);
+    }
+
+    #[test]
+    fn test_usage_mean_with_error() {
+        add_at(1, 2.0, "Fridge");
+        assert!(matches!(usage_mean_with_error(), Err(Error::NotFound { .. })));
+        for (ts, kwh) in [(2, 4.0), (3, 4.0), (4, 4.0), (5, 5.0), (6, 5.0), (7, 7.0), (8, 9.0)] {
+            add_at(ts, kwh, "Fridge");
+        }
+
+        // Sample variance 32 / 7 over 8 readings
+        let (mean, standard_error) = usage_mean_with_error().unwrap();
+        assert_eq!(mean, 5.0);
+        assert!((standard_error - (4.0f64 / 7.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        set_caller(user(1));
+        assert!(matches!(restore_backup(), Err(Error::NotFound { .. })));
+        let fridge = add_at(1, 2.0, "Fridge");
+        let heater = add_at(2, 8.0, "Heater");
+        assert_eq!(create_backup().unwrap(), 2);
+
+        delete_energy_usage(fridge.id).unwrap();
+        delete_energy_usage(heater.id).unwrap();
+        let later = add_at(3, 1.0, "Lamp");
+        assert_eq!(restore_backup().unwrap(), 2);
+
+        let restored: Vec<(u64, String)> =
+            records_by_time().into_iter().map(|usage| (usage.id, usage.device_type)).collect();
+        assert_eq!(
+            restored,
+            vec![(fridge.id, "Fridge".to_string()), (heater.id, "Heater".to_string())]
+        );
+        assert!(add_at(4, 1.0, "Lamp").id > later.id);
+
+        for caller in [user(2), Principal::anonymous()] {
+            set_caller(caller);
+            assert!(matches!(create_backup(), Err(Error::Unauthorized { .. })));
+            assert!(matches!(restore_backup(), Err(Error::Unauthorized { .. })));
+        }
+        assert_eq!(all_records().len(), 3);
+    }
+
+    #[test]
+    fn test_restore_backup_from_older_schema() {
+        set_caller(user(1));
+        let fridge = add_at(1, 2.0, "Fridge");
+        create_backup().unwrap();
+
+        // Rewrite the backup as if it had been taken before records had tags
+        let blob = BACKUP.with(|cell| cell.borrow().get().clone());
+        let mut args = candid::IDLArgs::from_bytes(&blob[1..]).unwrap();
+        let candid::IDLValue::Vec(records) = &mut args.args[0] else {
+            panic!("backups encode a vector of records");
+        };
+        for record in records.iter_mut() {
+            if let candid::IDLValue::Record(fields) = record {
+                fields.retain(|field| field.id.get_id() != candid::idl_hash("tags"));
+            }
+        }
+        let mut older = vec![BACKUP_VERSION];
+        older.extend(args.to_bytes().unwrap());
+        BACKUP.with(|cell| cell.borrow_mut().set(older)).unwrap();
+
+        delete_energy_usage(fridge.id).unwrap();
+        assert_eq!(restore_backup().unwrap(), 1);
+        let restored = get_energy_usage(fridge.id).unwrap();
+        assert_eq!((restored.usage_kwh, restored.owner), (2.0, user(1)));
+        assert!(restored.tags.is_empty());
+    }
+
+    #[test]
+    fn test_usage_by_tags() {
+        add_at(1, 3.0, "Fridge");
+        add_at(2, 5.0, "Heater");
+        add_at(3, 1.0, "Lamp");
+        tag_matching("Fridge".to_string(), "kitchen".to_string()).unwrap();
+        tag_matching("Fridge".to_string(), "always-on".to_string()).unwrap();
+        tag_matching("Heater".to_string(), "always-on".to_string()).unwrap();
+
+        assert_eq!(
+            usage_by_tags(vec!["always-on".to_string(), "kitchen".to_string(), "den".to_string()]),
+            vec![
+                ("always-on".to_string(), 8.0),
+                ("kitchen".to_string(), 3.0),
+                ("den".to_string(), 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_projected_annual_cost() {
+        add_at(0, 2.0, "Fridge");
+        assert!(matches!(projected_annual_cost(0.2), Err(Error::InvalidInput { .. })));
+        add_at(2 * NANOS_PER_DAY, 4.0, "Fridge");
+
+        // 3 kWh a day at 0.2 for a year
+        assert_eq!(projected_annual_cost(0.2).unwrap(), 219.0);
+        assert!(matches!(projected_annual_cost(-0.2), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_records_in_cost_range() {
+        set_caller(user(1));
+        set_tariff(0.3).unwrap();
+        add_at(1, 2.0, "Lamp");
+        let low_edge = add_at(2, 5.0, "Fridge");
+        let high_edge = add_at(3, 10.0, "Heater");
+        add_at(4, 11.0, "Heater");
+        let inside = add_at(5, 7.0, "Oven");
+
+        let ids: Vec<u64> =
+            records_in_cost_range(1.5, 3.0).unwrap().iter().map(|usage| usage.id).collect();
+        assert_eq!(ids, vec![low_edge.id, inside.id, high_edge.id]);
+        assert!(records_in_cost_range(3.0, 1.5).is_err());
+        assert!(records_in_cost_range(-1.0, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_equivalent_runtime() {
+        add_at(1, 0.25, "Lamp");
+        add_at(2, 0.75, "Lamp");
+
+        assert_eq!(equivalent_runtime(100).unwrap(), 10.0);
+        assert!(matches!(equivalent_runtime(0), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_mtd_comparison() {
+        add_at(date_ns(2024, 1, 2), 4.0, "Heater");
+        add_at(date_ns(2024, 1, 10) + NANOS_PER_HOUR * 20, 4.0, "Heater");
+        add_at(date_ns(2024, 1, 11), 9.0, "Heater");
+        add_at(date_ns(2024, 2, 3), 6.0, "Heater");
+        add_at(date_ns(2024, 2, 9), 4.0, "Heater");
+        set_time(date_ns(2024, 2, 10) + NANOS_PER_HOUR);
+
+        assert_eq!(
+            mtd_comparison(),
+            MtdComparison {
+                this_month_kwh: 10.0,
+                last_month_kwh: 8.0,
+                percent_change: 25.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_mtd_comparison_clamps_to_shorter_month() {
+        add_at(date_ns(2023, 2, 28), 5.0, "Heater");
+        add_at(date_ns(2023, 3, 1), 7.0, "Heater");
+        set_time(date_ns(2023, 3, 31));
+
+        let comparison = mtd_comparison();
+        assert_eq!(comparison.last_month_kwh, 5.0);
+        assert_eq!(comparison.this_month_kwh, 7.0);
+    }
+
+    #[test]
+    fn test_import_csv_with_quarantine() {
+        let csv = "timestamp,device_type,usage_kwh\n\
+                   100,Fridge,2.5\n\
+                   200,\"Heater, hall\",12\n\
+                   300,Fridge,-1\n\
+                   \n\
+                   oops\n";
+        assert!(matches!(import_csv(csv.to_string(), false), Err(Error::InvalidInput { .. })));
+        assert!(all_records().is_empty());
+
+        assert_eq!(import_csv(csv.to_string(), true).unwrap(), (2, 2));
+        let imported: Vec<(u64, String, f64)> = records_by_time()
+            .into_iter()
+            .map(|usage| (usage.timestamp, usage.device_type, usage.usage_kwh))
+            .collect();
+        assert_eq!(
+            imported,
+            vec![(100, "Fridge".to_string(), 2.5), (200, "Heater, hall".to_string(), 12.0)]
+        );
+        let quarantine = get_quarantine().rows;
+        assert_eq!(quarantine.len(), 2);
+        assert_eq!(quarantine[0].0, "300,Fridge,-1");
+        assert_eq!(quarantine[1], ("oops".to_string(), "Expected 3 fields, found 1.".to_string()));
+    }
+
+    #[test]
+    fn test_import_json_with_quarantine() {
+        let json = r#"[
+            {"timestamp": 100, "device_type": "Fridge", "usage_kwh": 2.0},
+            {"timestamp": 200, "device_type": "", "usage_kwh": 3.0},
+            {"timestamp": 300, "device_type": "Oven", "usage_kwh": 4.0},
+            {"device_type": "Oven"}
+        ]"#;
+        assert!(matches!(import_json(json.to_string(), false), Err(Error::InvalidInput { .. })));
+        assert!(all_records().is_empty());
+        assert!(import_json("{}".to_string(), true).is_err());
+
+        assert_eq!(import_json(json.to_string(), true).unwrap(), (2, 2));
+        assert_eq!(total_usage_kwh(), 6.0);
+        let quarantine = get_quarantine().rows;
+        assert_eq!(quarantine.len(), 2);
+        assert!(quarantine[0].0.contains("\"device_type\":\"\""));
+        assert!(quarantine[1].1.contains("timestamp"));
+    }
+
+    #[test]
+    fn test_get_and_clear_quarantine() {
+        import_csv("oops\n".to_string(), true).unwrap();
+        assert!(!get_quarantine().truncated);
+        QUARANTINE.with(|map| {
+            let mut map = map.borrow_mut();
+            for key in 1..2_500 {
+                let row = QuarantinedRow {
+                    raw_row: "x".repeat(MAX_QUARANTINE_ROW_LEN),
+                    reason: "y".repeat(MAX_QUARANTINE_REASON_LEN),
+                };
+                map.insert(key, row);
+            }
+        });
+        let page = get_quarantine();
+        assert!(page.truncated);
+        assert_eq!(page.rows[0].0, "oops");
+
+        set_caller(user(1));
+        assert_eq!(clear_quarantine().unwrap(), 2_500);
+        assert!(get_quarantine().rows.is_empty());
+        set_caller(user(2));
+        assert!(matches!(clear_quarantine(), Err(Error::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn test_cost_shares_by_device() {
+        assert!(cost_shares_by_device(0.2).unwrap().is_empty());
+        add_at(1, 3.0, "Fridge");
+        add_at(2, 9.0, "Heater");
+        let promo = add_at(3, 20.0, "EV");
+        set_cost_override(promo.id, Some(0.0)).unwrap();
+
+        let shares = cost_shares_by_device(0.5).unwrap();
+        assert_eq!(
+            shares,
+            vec![
+                ("Heater".to_string(), 75.0),
+                ("Fridge".to_string(), 25.0),
+                ("EV".to_string(), 0.0),
+            ]
+        );
+        let sum: f64 = shares.iter().map(|(_, share)| share).sum();
+        assert!((sum - 100.0).abs() < 1e-9);
+        assert!(cost_shares_by_device(0.0).unwrap().is_empty());
+        assert!(cost_shares_by_device(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_device_quota_status() {
+        set_caller(user(1));
+        set_time(date_ns(2024, 1, 31));
+        add_renewable(50.0, 0.0);
+        set_time(date_ns(2024, 2, 10));
+        set_device_quota("Heater".to_string(), 30.0).unwrap();
+        set_device_quota("Lamp".to_string(), 5.0).unwrap();
+        add_at(date_ns(2024, 2, 2), 20.0, "Heater");
+        add_at(date_ns(2024, 2, 9), 15.0, "Heater");
+        add_at(date_ns(2024, 2, 9), 2.0, "Lamp");
+
+        let status = device_quota_status();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].device_type, "Heater");
+        assert_eq!(status[0].month_usage_kwh, 35.0);
+        assert!(status[0].exceeded);
+        assert_eq!(status[1].month_usage_kwh, 2.0);
+        assert!(!status[1].exceeded);
+
+        set_device_quota("Lamp".to_string(), 0.0).unwrap();
+        assert_eq!(device_quota_status().len(), 1);
+        assert!(set_device_quota("Heater".to_string(), -1.0).is_err());
+        set_caller(user(2));
+        assert!(set_device_quota("Heater".to_string(), 10.0).is_err());
+    }
+
+    #[test]
+    fn test_weekend_share_percent() {
+        assert_eq!(weekend_share_percent(), 0.0);
+        assert_eq!(weekday(0), 3);
+        // 2024-03-01 was a Friday
+        add_at(date_ns(2024, 3, 1), 6.0, "Heater");
+        add_at(date_ns(2024, 3, 2) + 3_600 * 1_000_000_000, 3.0, "Heater");
+        add_at(date_ns(2024, 3, 3), 1.0, "Lamp");
+        add_at(date_ns(2024, 3, 4), 10.0, "Heater");
+        assert_eq!(weekend_share_percent(), 20.0);
+    }
+
+    #[test]
+    fn test_recommended_daily_target() {
+        set_caller(user(1));
+        let mid_month = date_ns(2024, 2, 10) + 12 * 3_600 * 1_000_000_000;
+        set_time(mid_month);
+        assert!(matches!(recommended_daily_target(), Err(Error::InvalidInput { .. })));
+
+        set_monthly_budget(100.0).unwrap();
+        add_at(date_ns(2024, 1, 31), 50.0, "Heater");
+        add_at(date_ns(2024, 2, 3), 40.0, "Heater");
+        set_time(mid_month);
+        // 60 kWh left over Feb 10..=29
+        assert_eq!(recommended_daily_target().unwrap(), 3.0);
+
+        add_at(date_ns(2024, 2, 9), 70.0, "Heater");
+        set_time(mid_month);
+        assert_eq!(recommended_daily_target().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_ownerless_records_and_assign_owner() {
+        let legacy = add_at(1, 2.0, "Heater");
+        set_caller(user(1));
+        add_at(2, 3.0, "Lamp");
+        let ownerless: Vec<u64> = ownerless_records().iter().map(|usage| usage.id).collect();
+        assert_eq!(ownerless, vec![legacy.id]);
+
+        assign_owner(legacy.id, user(2)).unwrap();
+        assert!(ownerless_records().is_empty());
+        assert_eq!(get_energy_usage(legacy.id).unwrap().owner, user(2));
+
+        assert!(matches!(
+            assign_owner(legacy.id, Principal::anonymous()),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(matches!(assign_owner(99, user(2)), Err(Error::NotFound { .. })));
+        set_caller(user(2));
+        assert!(assign_owner(legacy.id, user(3)).is_err());
+    }
+
+    #[test]
+    fn test_assign_owner_to_baseline_records() {
+        install_baseline_records(&[(4.0, "Heater"), (2.5, "Lamp")]);
+        post_upgrade(None);
+        set_caller(user(1));
+        let mine = add_at(2_000, 1.0, "Fridge");
+
+        let ownerless: Vec<u64> = ownerless_records().iter().map(|usage| usage.id).collect();
+        assert_eq!(ownerless, vec![0, 1]);
+        assert!(!ownerless.contains(&mine.id));
+
+        assign_owner(1, user(2)).unwrap();
+        let reassigned = get_energy_usage(1).unwrap();
+        assert_eq!((reassigned.owner, reassigned.usage_kwh), (user(2), 2.5));
+        assert_eq!(reassigned.device_type, "Lamp");
+        let ownerless: Vec<u64> = ownerless_records().iter().map(|usage| usage.id).collect();
+        assert_eq!(ownerless, vec![0]);
+    }
+
+    #[test]
+    fn test_personal_carbon_intensity() {
+        assert_eq!(personal_carbon_intensity(400.0).unwrap(), 0.0);
+        add_renewable(10.0, 50.0);
+        add_renewable(10.0, 0.0);
+        let intensity = personal_carbon_intensity(400.0).unwrap();
+        assert_eq!(intensity, 300.0);
+        assert!(intensity < 400.0);
+        assert!(personal_carbon_intensity(-1.0).is_err());
+
+        // Source emission factors apply here as in estimate_co2_kg
+        set_caller(user(1));
+        set_emission_factor("coal".to_string(), 1_000.0).unwrap();
+        add_energy_usage(EnergyUsagePayload {
+            usage_kwh: 20.0,
+            device_type: "Heater".to_string(),
+            energy_source: Some("coal".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        // (15 kWh at 400 g + 20 kWh at 1000 g) over 40 kWh
+        assert_eq!(personal_carbon_intensity(400.0).unwrap(), 650.0);
+    }
+
+    #[test]
+    fn test_tier_breakdown() {
+        for (ts, kwh) in [(1, 2.0), (2, 4.5), (3, 5.0), (4, 8.0), (5, 10.0), (6, 12.5)] {
+            add_at(ts, kwh, "Heater");
+        }
+        assert_eq!(
+            tier_breakdown(vec![5.0, 10.0]).unwrap(),
+            vec![
+                ("<5".to_string(), 2, 6.5),
+                ("5-10".to_string(), 2, 13.0),
+                (">=10".to_string(), 2, 22.5),
+            ]
+        );
+        assert!(tier_breakdown(Vec::new()).is_err());
+        assert!(tier_breakdown(vec![10.0, 5.0]).is_err());
+        assert!(tier_breakdown(vec![5.0, 5.0]).is_err());
+        assert!(tier_breakdown(vec![0.0, 5.0]).is_err());
+    }
+
+    #[test]
+    fn test_carbon_adjusted_cost() {
+        add_at(1, 40.0, "Heater");
+        add_at(2, 60.0, "EV");
+        // 100 kWh at 0.2 is 20.0; 100 kWh at 500 g/kWh is 50 kg at 0.1 is 5.0
+        assert_eq!(carbon_adjusted_cost(0.2, 0.1, 500.0).unwrap(), 25.0);
+        assert_eq!(carbon_adjusted_cost(0.2, 0.0, 500.0).unwrap(), 20.0);
+        assert!(carbon_adjusted_cost(-0.2, 0.1, 500.0).is_err());
+        assert!(carbon_adjusted_cost(0.2, -0.1, 500.0).is_err());
+        assert!(carbon_adjusted_cost(0.2, 0.1, -500.0).is_err());
+
+        // CO2 follows estimate_co2_kg, source emission factors included
+        set_caller(user(1));
+        set_emission_factor("solar".to_string(), 0.0).unwrap();
+        add_energy_usage(EnergyUsagePayload {
+            usage_kwh: 50.0,
+            device_type: "Heater".to_string(),
+            energy_source: Some("solar".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(estimate_co2_kg(Some(500.0)).unwrap(), 50.0);
+        assert_eq!(carbon_adjusted_cost(0.2, 0.1, 500.0).unwrap(), 35.0);
+    }
+
+    #[test]
+    fn test_longest_low_usage_streak() {
+        assert_eq!(longest_low_usage_streak(5.0).unwrap(), 0);
+        let day = |n: u64| n * NANOS_PER_DAY;
+        add_at(day(0), 2.0, "Heater");
+        add_at(day(1), 3.0, "Heater");
+        add_at(day(2), 9.0, "Heater");
+        // Days 3 and 5 have no readings, so 3..=6 is the longest run
+        add_at(day(4), 1.0, "Lamp");
+        add_at(day(4), 2.0, "Heater");
+        add_at(day(6), 4.0, "Heater");
+        add_at(day(7), 6.0, "Heater");
+        add_at(day(8), 1.0, "Heater");
+        assert_eq!(longest_low_usage_streak(5.0).unwrap(), 4);
+        assert_eq!(longest_low_usage_streak(3.5).unwrap(), 3);
+        assert!(longest_low_usage_streak(0.0).is_err());
+    }
+
+    #[test]
+    fn test_savings_vs_last_month() {
+        add_at(date_ns(2024, 2, 5), 30.0, "Heater");
+        assert!(matches!(savings_vs_last_month(0.5), Err(Error::InvalidInput { .. })));
+
+        add_at(date_ns(2023, 12, 20), 100.0, "Heater");
+        add_at(date_ns(2024, 1, 10), 40.0, "Heater");
+        add_at(date_ns(2024, 1, 25), 20.0, "EV");
+        add_at(date_ns(2024, 2, 12), 10.0, "EV");
+        // January cost 30.0, February so far 20.0
+        assert_eq!(savings_vs_last_month(0.5).unwrap(), 10.0);
+
+        add_at(date_ns(2024, 2, 14), 40.0, "Heater");
+        assert_eq!(savings_vs_last_month(0.5).unwrap(), -10.0);
+        assert!(savings_vs_last_month(-0.5).is_err());
+    }
+
+    // Encode a fully populated record with the named fields left out, the way
+    // a canister running an older schema would have stored it, and read it back
+    fn decode_without(fields: &[&str]) -> EnergyUsage {
+        let usage = EnergyUsage {
+            id: 7,
+            usage_kwh: 3.5,
+            timestamp: 42,
+            device_type: "Heater".to_string(),
+            recommendation: Some("Turn it down.".to_string()),
+            is_adjustment: true,
+            renewable_percent: 40.0,
+            owner: user(1),
+            estimated: true,
+            components: vec![1.5, 2.0],
+            tags: vec!["winter".to_string()],
+            energy_source: "solar".to_string(),
+            cost_override: Some(1.0),
+            attachment_url: Some("https://example.com/meter.jpg".to_string()),
+        };
+        let mut args = candid::IDLArgs::from_bytes(&usage.to_bytes()).unwrap();
+        let candid::IDLValue::Record(record) = &mut args.args[0] else {
+            panic!("records encode as Candid records");
+        };
+        let hashes: Vec<u32> = fields.iter().map(|field| candid::idl_hash(field)).collect();
+        record.retain(|field| !hashes.contains(&field.id.get_id()));
+        EnergyUsage::from_bytes(Cow::Owned(args.to_bytes().unwrap()))
+    }
+
+    #[test]
+    fn test_decode_records_missing_later_fields() {
+        let full = decode_without(&[]);
+        assert!(full.is_adjustment);
+        assert_eq!(full.tags, vec!["winter".to_string()]);
+
+        let usage = decode_without(&["is_adjustment"]);
+        assert_eq!((usage.id, usage.usage_kwh), (7, 3.5));
+        assert!(!usage.is_adjustment);
+
+        let usage = decode_without(&["renewable_percent"]);
+        assert_eq!(usage.renewable_percent, 0.0);
+        assert_eq!(renewable_kwh(&usage), 0.0);
+
+        let usage = decode_without(&["owner"]);
+        assert_eq!(usage.owner, Principal::anonymous());
+
+        assert!(!decode_without(&["estimated"]).estimated);
+
+        assert!(decode_without(&["components"]).components.is_empty());
+
+        assert!(decode_without(&["tags"]).tags.is_empty());
+
+        let usage = decode_without(&["energy_source"]);
+        assert!(usage.energy_source.is_empty());
     }
 }